@@ -0,0 +1,262 @@
+use std::cell::Cell;
+#[cfg(feature = "alloc-tracking")]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "alloc-tracking")]
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// What memory is being allocated for. Each allocation is charged to the
+/// subsystem its thread was working in at the time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Subsystem {
+    Other,
+    /// Reading, parsing and serialising messages
+    Protocol,
+    /// Running commands, apart from their storage calls
+    Commands,
+    /// The storage engine and the values it hands out
+    #[cfg_attr(not(feature = "alloc-tracking"), allow(dead_code))]
+    Storage,
+}
+
+impl Subsystem {
+    #[cfg_attr(not(feature = "alloc-tracking"), allow(dead_code))]
+    pub const ALL: [Subsystem; 4] = [Subsystem::Other, Subsystem::Protocol, Subsystem::Commands, Subsystem::Storage];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Other => "other",
+            Subsystem::Protocol => "protocol",
+            Subsystem::Commands => "commands",
+            Subsystem::Storage => "storage",
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: Cell<Subsystem> = const { Cell::new(Subsystem::Other) };
+}
+
+/// Charges this thread's allocations to a subsystem until dropped, then goes
+/// back to the one before.
+pub(crate) struct Scope(Subsystem);
+
+pub(crate) fn scope(subsystem: Subsystem) -> Scope {
+    Scope(CURRENT.replace(subsystem))
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.set(self.0);
+    }
+}
+
+/// A subsystem's share of the heap
+pub(crate) struct AllocStats {
+    pub subsystem: Subsystem,
+    /// Bytes currently allocated
+    pub allocated: usize,
+    /// Allocations made since startup
+    pub allocations: u64,
+}
+
+/// Where the heap is going, or `None` if this build doesn't track it.
+#[cfg(not(feature = "alloc-tracking"))]
+pub(crate) fn stats() -> Option<Vec<AllocStats>> {
+    None
+}
+
+#[cfg(feature = "alloc-tracking")]
+pub(crate) fn stats() -> Option<Vec<AllocStats>> {
+    let stats = Subsystem::ALL
+        .iter()
+        .zip(&COUNTERS)
+        .map(|(subsystem, counters)| AllocStats {
+            subsystem: *subsystem,
+            allocated: counters.allocated.load(Ordering::Relaxed),
+            allocations: counters.allocations.load(Ordering::Relaxed),
+        })
+        .collect();
+    Some(stats)
+}
+
+/// Charges the heap buffer of `value` to `subsystem` from now on, moving its
+/// bytes and its allocation over from whichever subsystem made it. For
+/// buffers that change hands, such as a SET's value, which is allocated
+/// while the request is read and then moved into storage.
+#[cfg(feature = "alloc-tracking")]
+pub(crate) fn recharge<T>(value: &mut Vec<T>, subsystem: Subsystem) {
+    let size = value.capacity() * size_of::<T>();
+    // Nothing is allocated for empty buffers or zero sized types
+    if size == 0 {
+        return;
+    }
+    // Every heap allocation comes from `TrackingAllocator`, so the buffer has
+    // a tag in front of it. Holding `&mut` means nothing can free or grow it
+    // meanwhile.
+    let tag = unsafe { value.as_mut_ptr().cast::<u8>().sub(TAG_SIZE).cast::<usize>() };
+    let previous = unsafe { tag.read() };
+    if previous == subsystem as usize {
+        return;
+    }
+    unsafe { tag.write(subsystem as usize) };
+    let (from, to) = (&COUNTERS[previous], &COUNTERS[subsystem as usize]);
+    from.allocated.fetch_sub(size, Ordering::Relaxed);
+    from.allocations.fetch_sub(1, Ordering::Relaxed);
+    to.allocated.fetch_add(size, Ordering::Relaxed);
+    to.allocations.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(feature = "alloc-tracking")]
+struct Counters {
+    allocated: AtomicUsize,
+    allocations: AtomicU64,
+}
+
+/// Indexed by `Subsystem as usize`
+#[cfg(feature = "alloc-tracking")]
+static COUNTERS: [Counters; Subsystem::ALL.len()] =
+    [const { Counters { allocated: AtomicUsize::new(0), allocations: AtomicU64::new(0) } }; Subsystem::ALL.len()];
+
+#[cfg(feature = "alloc-tracking")]
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+/// Wraps the system allocator, counting bytes per subsystem.
+///
+/// Each allocation is preceded by a header recording the subsystem it was
+/// charged to, so it is credited back to that subsystem however far it
+/// travels before being freed, e.g. a value read from storage and dropped
+/// after its reply is written.
+#[cfg(feature = "alloc-tracking")]
+struct TrackingAllocator;
+
+#[cfg(feature = "alloc-tracking")]
+const TAG_SIZE: usize = size_of::<usize>();
+
+/// Header bytes in front of an allocation: room for the tag, rounded up so
+/// the allocation itself stays aligned.
+#[cfg(feature = "alloc-tracking")]
+fn header_size(layout: Layout) -> usize {
+    layout.align().max(TAG_SIZE)
+}
+
+#[cfg(feature = "alloc-tracking")]
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let header = header_size(layout);
+        let Some(outer) = layout
+            .size()
+            .checked_add(header)
+            .and_then(|size| Layout::from_size_align(size, layout.align()).ok())
+        else {
+            return std::ptr::null_mut();
+        };
+        let base = System.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        // The thread may be tearing down its locals
+        let subsystem = CURRENT.try_with(Cell::get).unwrap_or(Subsystem::Other);
+        // header is a multiple of TAG_SIZE, so the tag is aligned
+        base.add(header - TAG_SIZE).cast::<usize>().write(subsystem as usize);
+        let counters = &COUNTERS[subsystem as usize];
+        counters.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+        base.add(header)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = header_size(layout);
+        let subsystem = ptr.sub(TAG_SIZE).cast::<usize>().read();
+        COUNTERS[subsystem].allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        let outer = Layout::from_size_align_unchecked(layout.size() + header, layout.align());
+        System.dealloc(ptr.sub(header), outer);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let header = header_size(layout);
+        let Some(new_outer_size) = new_size.checked_add(header) else {
+            return std::ptr::null_mut();
+        };
+        let subsystem = ptr.sub(TAG_SIZE).cast::<usize>().read();
+        let outer = Layout::from_size_align_unchecked(layout.size() + header, layout.align());
+        let base = System.realloc(ptr.sub(header), outer, new_outer_size);
+        if base.is_null() {
+            return base;
+        }
+        // Growing stays charged to whoever made the allocation
+        let allocated = &COUNTERS[subsystem].allocated;
+        allocated.fetch_add(new_size, Ordering::Relaxed);
+        allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        base.add(header)
+    }
+}
+
+/// Held by tests that check the storage figure, which would otherwise see
+/// each other's allocations.
+#[cfg(all(test, feature = "alloc-tracking"))]
+pub(crate) static STORAGE_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(all(test, feature = "alloc-tracking"))]
+mod test {
+    use super::*;
+
+    fn allocated(subsystem: Subsystem) -> usize {
+        stats().unwrap()[subsystem as usize].allocated
+    }
+
+    #[test]
+    fn test_allocations_are_charged_to_their_scope() {
+        let _lock = STORAGE_TEST_LOCK.lock().unwrap();
+        let before = allocated(Subsystem::Storage);
+        let mut value = {
+            let _scope = scope(Subsystem::Storage);
+            vec![0u8; 1000]
+        };
+        assert_eq!(allocated(Subsystem::Storage), before + 1000);
+
+        // Growing and freeing it outside the scope still credits storage
+        value.reserve_exact(9000);
+        assert_eq!(allocated(Subsystem::Storage), before + value.capacity());
+        drop(value);
+        assert_eq!(allocated(Subsystem::Storage), before);
+    }
+
+    #[test]
+    fn test_recharge() {
+        let _lock = STORAGE_TEST_LOCK.lock().unwrap();
+        let before = (allocated(Subsystem::Commands), allocated(Subsystem::Storage));
+        let mut value = {
+            let _scope = scope(Subsystem::Commands);
+            Vec::<u64>::with_capacity(1000)
+        };
+        recharge(&mut value, Subsystem::Storage);
+        assert_eq!((allocated(Subsystem::Commands), allocated(Subsystem::Storage)), (before.0, before.1 + 8000));
+        // Freed as storage's
+        drop(value);
+        assert_eq!(allocated(Subsystem::Storage), before.1);
+        recharge(&mut Vec::<u8>::new(), Subsystem::Storage);
+    }
+
+    #[test]
+    fn test_scopes_nest() {
+        let _outer = scope(Subsystem::Protocol);
+        {
+            let _inner = scope(Subsystem::Commands);
+            assert_eq!(CURRENT.get(), Subsystem::Commands);
+        }
+        assert_eq!(CURRENT.get(), Subsystem::Protocol);
+    }
+
+    #[test]
+    fn test_aligned_allocations() {
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        unsafe {
+            let ptr = ALLOCATOR.alloc(layout);
+            assert_eq!(ptr as usize % 64, 0);
+            let ptr = ALLOCATOR.realloc(ptr, layout, 200);
+            assert_eq!(ptr as usize % 64, 0);
+            ALLOCATOR.dealloc(ptr, Layout::from_size_align(200, 64).unwrap());
+        }
+    }
+}
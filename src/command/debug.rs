@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use super::{over_budget, BUDGET_CHECK_INTERVAL};
+use crate::message::{parse_message, Message};
+use crate::storage::{StorageError, Value as StoredValue, DB};
+use crate::util::glob_match;
+
+/// Every DEBUG subcommand. Each takes its own arguments, so they are
+/// checked here rather than by the command spec.
+pub(super) const SUBCOMMANDS: &[&str] = &["json-export", "json-import", "sharing", "stringmatch", "protocol-parse"];
+
+/// Runs a DEBUG subcommand.
+///
+/// Besides the dataset JSON dumps and the report on shared values, DEBUG
+/// exposes internal algorithms so fuzz
+/// targets can drive them in-process through a real connection:
+/// STRINGMATCH runs the glob matcher, and PROTOCOL-PARSE runs the RESP
+/// parser over its payload.
+pub(super) fn execute(db: &DB, subcommand: &str, args: &[&[u8]], budget: Duration) -> Result<Message, StorageError> {
+    let message = match (subcommand, args) {
+        ("json-export", []) => scan_reply("JSON-EXPORT", export_json(db, None, budget).map(json_reply))?,
+        ("json-export", [pattern]) => {
+            scan_reply("JSON-EXPORT", export_json(db, Some(pattern), budget).map(json_reply))?
+        }
+        ("json-import", [json]) => match import_json(db, json) {
+            Ok(count) => Message::Integer(count as isize),
+            Err(e) => Message::Error(format!("ERR {}", e)),
+        },
+        ("sharing", []) => scan_reply("SHARING", sharing(db, budget))?,
+        ("stringmatch", [pattern, string]) => {
+            Message::Integer(glob_match(pattern, string).into())
+        }
+        ("protocol-parse", [payload]) => protocol_parse(payload),
+        _ => Message::Error(format!(
+            "ERR Wrong number of arguments for DEBUG {}",
+            subcommand.to_uppercase()
+        )),
+    };
+    Ok(message)
+}
+
+/// Parses the first message in `payload`, replying with it as JSON and how
+/// many bytes it took up.
+fn protocol_parse(payload: &[u8]) -> Message {
+    match parse_message(payload) {
+        Ok((remaining, message)) => Message::Array(Some(vec![
+            Message::BulkString(Some(message.to_json().to_string().into_bytes())),
+            Message::Integer((payload.len() - remaining.len()) as isize),
+        ])),
+        Err(e) => Message::Error(format!("ERR {}", e)),
+    }
+}
+
+fn json_reply(json: String) -> Message {
+    Message::BulkString(Some(json.into_bytes()))
+}
+
+/// Replies to a subcommand that scans the keyspace. Running out of budget is
+/// an error for the client; a storage failure is passed on.
+fn scan_reply(subcommand: &str, reply: Result<Message, ScanError>) -> Result<Message, StorageError> {
+    match reply {
+        Ok(reply) => Ok(reply),
+        Err(ScanError::Storage(e)) => Err(e),
+        Err(e) => Ok(Message::Error(format!("ERR DEBUG {} {}", subcommand, e))),
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ScanError {
+    #[error("aborted after exceeding the command time budget")]
+    OverBudget,
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ImportError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("the dataset must be a JSON object keyed by key name")]
+    NotAnObject,
+
+    #[error("invalid entry for key {0}: {1}")]
+    InvalidEntry(String, &'static str),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Renders the keys matching `pattern`, or every key, as a JSON object:
+/// `{"key": {"type": "string", "value": "...", "ttl": -1}}`. Keys come out
+/// sorted so exports from different servers can be diffed. Like KEYS, the
+/// export gives up once it has run for longer than `budget`.
+pub(crate) fn export_json(db: &DB, pattern: Option<&[u8]>, budget: Duration) -> Result<String, ScanError> {
+    let mut out_of_time = budget_check(budget);
+    let keys = matching_keys(db, pattern, &mut out_of_time)?;
+    let mut dataset = Map::new();
+    for key in keys {
+        if out_of_time() {
+            return Err(ScanError::OverBudget);
+        }
+        if let Some(value) = db.get(&key)? {
+            let entry = json!({ "type": "string", "value": String::from_utf8_lossy(&value), "ttl": -1 });
+            dataset.insert(String::from_utf8_lossy(&key).into_owned(), entry);
+        }
+    }
+    Ok(Value::Object(dataset).to_string())
+}
+
+/// Reports what COPY's value sharing saves, as a flat array of names and
+/// values: how many keys there are, how many of them share their value with
+/// another key, how many distinct values those share, and how many more
+/// bytes giving each key its own copy would take. Gives up once it has run
+/// for longer than `budget`.
+pub(crate) fn sharing(db: &DB, budget: Duration) -> Result<Message, ScanError> {
+    let mut out_of_time = budget_check(budget);
+    let keys = matching_keys(db, None, &mut out_of_time)?;
+    let mut total = 0;
+    // Values with more than one reference, by address, with how many keys
+    // hold them. Each is kept alive so its address can't be reused.
+    let mut shared: HashMap<*const Vec<u8>, (StoredValue, usize)> = HashMap::new();
+    for key in keys {
+        if out_of_time() {
+            return Err(ScanError::OverBudget);
+        }
+        match db.refcount(&key)? {
+            None => {}
+            Some(1) => total += 1,
+            Some(_) => {
+                if let Some(value) = db.get(&key)? {
+                    total += 1;
+                    shared.entry(Arc::as_ptr(&value)).or_insert((value, 0)).1 += 1;
+                }
+            }
+        }
+    }
+
+    // A value can have more than one reference while only one key holds it
+    shared.retain(|_, (_, keys)| *keys > 1);
+    let shared_keys: usize = shared.values().map(|(_, keys)| keys).sum();
+    let bytes_saved: usize = shared.values().map(|(value, keys)| value.len() * (keys - 1)).sum();
+    let field = |name: &str| Message::BulkString(Some(name.into()));
+    Ok(Message::Array(Some(vec![
+        field("keys"),
+        Message::Integer(total as isize),
+        field("shared_keys"),
+        Message::Integer(shared_keys as isize),
+        field("shared_values"),
+        Message::Integer(shared.len() as isize),
+        field("bytes_saved"),
+        Message::Integer(bytes_saved as isize),
+    ])))
+}
+
+/// Counts the items a scan visits, returning true once every
+/// `BUDGET_CHECK_INTERVAL` of them if the scan has run out of `budget`.
+fn budget_check(budget: Duration) -> impl FnMut() -> bool {
+    let start = Instant::now();
+    let mut visited = 0usize;
+    move || {
+        visited += 1;
+        visited.is_multiple_of(BUDGET_CHECK_INTERVAL) && over_budget(start, budget)
+    }
+}
+
+/// Collects the keys matching `pattern`, or every key, so no storage lock is
+/// held while their values are read.
+fn matching_keys(
+    db: &DB,
+    pattern: Option<&[u8]>,
+    out_of_time: &mut impl FnMut() -> bool,
+) -> Result<Vec<Vec<u8>>, ScanError> {
+    let mut keys = Vec::new();
+    let mut aborted = false;
+    db.scan(&mut |key| {
+        if out_of_time() {
+            aborted = true;
+            return ControlFlow::Break(());
+        }
+        if pattern.is_none_or(|pattern| glob_match(pattern, key)) {
+            keys.push(key.to_vec());
+        }
+        ControlFlow::Continue(())
+    })?;
+    if aborted {
+        return Err(ScanError::OverBudget);
+    }
+    Ok(keys)
+}
+
+/// Loads a dataset in the form `export_json` produces, returning how many
+/// keys were written. Nothing is written unless every entry is valid. If
+/// storage fails partway through, the keys already written are put back as
+/// they were. The import isn't atomic: other clients can see it half done.
+pub(crate) fn import_json(db: &DB, json: &[u8]) -> Result<usize, ImportError> {
+    let Value::Object(dataset) = serde_json::from_slice(json)? else {
+        return Err(ImportError::NotAnObject);
+    };
+
+    let mut entries = Vec::with_capacity(dataset.len());
+    for (key, entry) in &dataset {
+        let invalid = |reason| ImportError::InvalidEntry(key.clone(), reason);
+        if entry.get("type").and_then(Value::as_str) != Some("string") {
+            return Err(invalid("only string values are supported"));
+        }
+        let value = entry.get("value").and_then(Value::as_str).ok_or_else(|| invalid("value must be a string"))?;
+        // -1 is what TTL reports for a key without an expiry
+        match entry.get("ttl") {
+            None | Some(Value::Null) => {}
+            Some(ttl) if ttl.as_i64() == Some(-1) => {}
+            Some(_) => return Err(invalid("keys with a TTL are not supported")),
+        }
+        entries.push((key, value));
+    }
+
+    // Each key's value from before the import, to undo it with
+    let mut written = Vec::with_capacity(entries.len());
+    for (key, value) in &entries {
+        let result = db.get(key.as_bytes()).and_then(|previous| {
+            db.set(key.as_bytes(), value.as_bytes().to_vec())?;
+            Ok(previous)
+        });
+        match result {
+            Ok(previous) => written.push((key.as_bytes(), previous)),
+            Err(e) => {
+                roll_back(db, written);
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(entries.len())
+}
+
+/// Restores keys a failed import overwrote, latest first.
+fn roll_back(db: &DB, written: Vec<(&[u8], Option<StoredValue>)>) {
+    for (key, previous) in written.into_iter().rev() {
+        let restored = match previous {
+            Some(value) => db.set(key, Arc::unwrap_or_clone(value)),
+            None => db.del(key).map(drop),
+        };
+        if let Err(e) = restored {
+            eprintln!("Failed to roll back JSON-IMPORT of {}: {}", String::from_utf8_lossy(key), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{MemoryStorage, Storage};
+
+    /// Memory storage that fails every write to one key
+    struct FailingStorage {
+        storage: MemoryStorage,
+        failing_key: &'static [u8],
+    }
+
+    impl FailingStorage {
+        fn write(&self, key: &[u8]) -> Result<(), StorageError> {
+            if key == self.failing_key {
+                return Err(StorageError::Backend("disk full".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    impl Storage for FailingStorage {
+        fn get(&self, key: &[u8]) -> Result<Option<StoredValue>, StorageError> {
+            self.storage.get(key)
+        }
+
+        fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+            self.write(key)?;
+            self.storage.set(key, value)
+        }
+
+        fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError> {
+            self.write(key)?;
+            self.storage.update(key, update)
+        }
+
+        fn del(&self, key: &[u8]) -> Result<bool, StorageError> {
+            self.write(key)?;
+            self.storage.del(key)
+        }
+
+        fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError> {
+            self.write(destination)?;
+            self.storage.copy(source, destination, replace)
+        }
+
+        fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError> {
+            self.storage.scan(visit)
+        }
+    }
+
+    fn db(entries: &[(&str, &str)]) -> DB {
+        let db: DB = Arc::new(MemoryStorage::new());
+        for (key, value) in entries {
+            db.set(key.as_bytes(), value.as_bytes().to_vec()).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn test_fuzz_hooks() {
+        let db = db(&[]);
+        let run = |subcommand, args: &[&str]| {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            execute(&db, subcommand, &args, Duration::ZERO).unwrap()
+        };
+
+        assert_eq!(run("stringmatch", &["h?llo*", "hello world"]), Message::Integer(1));
+        assert_eq!(run("stringmatch", &["[a-", "b"]), Message::Integer(0));
+
+        assert_eq!(
+            run("protocol-parse", &["*2\r\n:1\r\n+OK\r\ntrailing"]),
+            Message::Array(Some(vec![
+                Message::BulkString(Some(r#"[1,{"simple":"OK"}]"#.into())),
+                Message::Integer(13),
+            ]))
+        );
+        assert_eq!(run("protocol-parse", &["$5\r\nab"]), Message::Error("ERR incomplete message".to_string()));
+        assert_eq!(run("protocol-parse", &["!"]), Message::Error("ERR Protocol error: unknown message type".to_string()));
+
+        assert_eq!(
+            run("stringmatch", &["*"]),
+            Message::Error("ERR Wrong number of arguments for DEBUG STRINGMATCH".to_string())
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let db = db(&[("user:2", "bob"), ("user:1", "alice \"al\""), ("session", "x")]);
+        assert_eq!(
+            export_json(&db, Some(b"user:*"), Duration::ZERO).unwrap(),
+            r#"{"user:1":{"ttl":-1,"type":"string","value":"alice \"al\""},"user:2":{"ttl":-1,"type":"string","value":"bob"}}"#
+        );
+        assert_eq!(export_json(&db, Some(b"nothing*"), Duration::ZERO).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_export_over_budget() {
+        let db = db(&[]);
+        for i in 0..2 * BUDGET_CHECK_INTERVAL {
+            db.set(format!("key:{}", i).as_bytes(), Vec::new()).unwrap();
+        }
+        assert!(matches!(export_json(&db, None, Duration::from_nanos(1)), Err(ScanError::OverBudget)));
+        assert_eq!(
+            execute(&db, "json-export", &[], Duration::from_nanos(1)).unwrap(),
+            Message::Error("ERR DEBUG JSON-EXPORT aborted after exceeding the command time budget".to_string())
+        );
+        assert!(matches!(sharing(&db, Duration::from_nanos(1)), Err(ScanError::OverBudget)));
+    }
+
+    #[test]
+    fn test_sharing() {
+        let db = db(&[("a", "12345"), ("b", "xy"), ("unique", "value")]);
+        db.copy(b"a", b"a2", false).unwrap();
+        db.copy(b"a", b"a3", false).unwrap();
+        db.copy(b"b", b"b2", false).unwrap();
+        // Held by a reader, but by only one key
+        let _reader = db.get(b"unique").unwrap();
+        let field = |name: &str| Message::BulkString(Some(name.into()));
+        assert_eq!(
+            sharing(&db, Duration::ZERO).unwrap(),
+            Message::Array(Some(vec![
+                field("keys"),
+                Message::Integer(6),
+                field("shared_keys"),
+                Message::Integer(5),
+                field("shared_values"),
+                Message::Integer(2),
+                field("bytes_saved"),
+                Message::Integer(5 * 2 + 2),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_import_round_trip() {
+        let source = db(&[("a", "1"), ("b", "two"), ("c", "")]);
+        let json = export_json(&source, None, Duration::ZERO).unwrap();
+
+        let target = db(&[("a", "old")]);
+        assert_eq!(import_json(&target, json.as_bytes()).unwrap(), 3);
+        assert_eq!(export_json(&target, None, Duration::ZERO).unwrap(), json);
+    }
+
+    #[test]
+    fn test_import_is_all_or_nothing() {
+        let target = db(&[]);
+        for json in [
+            "[]",
+            "{\"a\":",
+            r#"{"a":{"type":"string","value":"1"},"b":{"type":"list","value":"2"}}"#,
+            r#"{"a":{"type":"string","value":1}}"#,
+            r#"{"a":{"type":"string","value":"1","ttl":100}}"#,
+        ] {
+            assert!(import_json(&target, json.as_bytes()).is_err(), "{}", json);
+        }
+        assert_eq!(export_json(&target, None, Duration::ZERO).unwrap(), "{}");
+
+        // Keys written before a storage failure are put back
+        let storage = FailingStorage { storage: MemoryStorage::new(), failing_key: b"c" };
+        storage.storage.set(b"a", b"old".to_vec()).unwrap();
+        let failing: DB = Arc::new(storage);
+        let json = r#"{"a":{"type":"string","value":"1"},"b":{"type":"string","value":"2"},"c":{"type":"string","value":"3"}}"#;
+        assert!(matches!(import_json(&failing, json.as_bytes()), Err(ImportError::Storage(_))));
+        assert_eq!(export_json(&failing, None, Duration::ZERO).unwrap(), r#"{"a":{"ttl":-1,"type":"string","value":"old"}}"#);
+
+        // A missing or null TTL means no expiry
+        let json = r#"{"a":{"type":"string","value":"1"},"b":{"type":"string","value":"2","ttl":null}}"#;
+        assert_eq!(import_json(&target, json.as_bytes()).unwrap(), 2);
+    }
+}
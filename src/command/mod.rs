@@ -0,0 +1,783 @@
+use std::mem;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::allocator;
+use crate::config;
+use crate::message::Message;
+use crate::shutdown::SHUTDOWN;
+use crate::stats::STATS;
+use crate::storage::{StorageError, DB};
+use crate::util::{glob_match, normalise_string_range, popcount, popcount_bits, reserve_growth};
+
+mod debug;
+mod spec;
+use spec::{ArgSpec, CommandSpec};
+
+/// Every command the server accepts. INFO reports them in this order.
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "ping",
+        min_args: 0,
+        max_args: Some(1),
+        args: &[ArgSpec::Optional(&[ArgSpec::String])],
+        build: |args| Command::PING(args.optional_string(0)),
+    },
+    CommandSpec {
+        name: "echo",
+        min_args: 1,
+        max_args: Some(1),
+        args: &[ArgSpec::String],
+        build: |args| Command::ECHO(args.string(0)),
+    },
+    CommandSpec {
+        name: "set",
+        min_args: 2,
+        max_args: Some(2),
+        args: &[ArgSpec::String, ArgSpec::Value],
+        build: |args| Command::SET(args.string(0), args.value(1)),
+    },
+    CommandSpec {
+        name: "append",
+        min_args: 2,
+        max_args: Some(2),
+        args: &[ArgSpec::String, ArgSpec::String],
+        build: |args| Command::APPEND(args.string(0), args.string(1)),
+    },
+    CommandSpec {
+        name: "setrange",
+        min_args: 3,
+        max_args: Some(3),
+        args: &[ArgSpec::String, ArgSpec::Integer, ArgSpec::String],
+        build: |args| Command::SETRANGE(args.string(0), args.integer(1), args.string(2)),
+    },
+    CommandSpec {
+        name: "del",
+        min_args: 1,
+        max_args: None,
+        args: &[ArgSpec::String, ArgSpec::Variadic(&ArgSpec::String)],
+        build: |args| Command::DEL(args.strings_from(0)),
+    },
+    CommandSpec {
+        name: "copy",
+        min_args: 2,
+        max_args: Some(3),
+        args: &[ArgSpec::String, ArgSpec::String, ArgSpec::Optional(&[ArgSpec::Token(&["replace"])])],
+        build: |args| Command::COPY(args.string(0), args.string(1), args.optional_token(2).is_some()),
+    },
+    CommandSpec {
+        name: "get",
+        min_args: 1,
+        max_args: Some(1),
+        args: &[ArgSpec::String],
+        build: |args| Command::GET(args.string(0)),
+    },
+    CommandSpec {
+        name: "getrange",
+        min_args: 3,
+        max_args: Some(3),
+        args: &[ArgSpec::String, ArgSpec::Integer, ArgSpec::Integer],
+        build: |args| Command::GETRANGE(args.string(0), args.integer(1), args.integer(2)),
+    },
+    CommandSpec {
+        name: "substr",
+        min_args: 3,
+        max_args: Some(3),
+        args: &[ArgSpec::String, ArgSpec::Integer, ArgSpec::Integer],
+        build: |args| Command::GETRANGE(args.string(0), args.integer(1), args.integer(2)),
+    },
+    CommandSpec {
+        name: "bitcount",
+        min_args: 1,
+        max_args: Some(4),
+        args: &[
+            ArgSpec::String,
+            ArgSpec::Optional(&[
+                ArgSpec::Integer,
+                ArgSpec::Integer,
+                ArgSpec::Optional(&[ArgSpec::Token(&["byte", "bit"])]),
+            ]),
+        ],
+        build: |args| Command::BITCOUNT(args.string(0), args.optional_range(1)),
+    },
+    CommandSpec {
+        name: "keys",
+        min_args: 1,
+        max_args: Some(1),
+        args: &[ArgSpec::String],
+        build: |args| Command::KEYS(args.string(0)),
+    },
+    CommandSpec {
+        name: "client",
+        min_args: 2,
+        max_args: Some(2),
+        args: &[ArgSpec::Token(&["reply"]), ArgSpec::Token(&["on", "off", "skip"])],
+        build: |args| Command::CLIENT(ClientCommand::Reply(match args.token(1) {
+            "on" => ReplyMode::On,
+            "off" => ReplyMode::Off,
+            _ => ReplyMode::Skip,
+        })),
+    },
+    CommandSpec {
+        name: "debug",
+        min_args: 1,
+        max_args: None,
+        args: &[ArgSpec::Token(debug::SUBCOMMANDS), ArgSpec::Variadic(&ArgSpec::String)],
+        build: |args| Command::DEBUG(args.token(0), args.strings_from(1)),
+    },
+    CommandSpec {
+        name: "stats",
+        min_args: 1,
+        max_args: Some(1),
+        args: &[ArgSpec::Token(&["prefix"])],
+        build: |_| Command::STATS(StatsCommand::Prefix),
+    },
+    CommandSpec {
+        name: "memory",
+        min_args: 1,
+        max_args: Some(1),
+        args: &[ArgSpec::Token(&["stats"])],
+        build: |_| Command::MEMORY(MemoryCommand::Stats),
+    },
+    CommandSpec {
+        name: "object",
+        min_args: 2,
+        max_args: Some(2),
+        args: &[ArgSpec::Token(&["refcount"]), ArgSpec::String],
+        build: |args| Command::OBJECT(ObjectCommand::Refcount(args.string(1))),
+    },
+    CommandSpec {
+        name: "shutdown",
+        min_args: 0,
+        max_args: Some(1),
+        args: &[ArgSpec::Optional(&[ArgSpec::Token(&["drain"])])],
+        build: |args| Command::SHUTDOWN(match args.optional_token(0) {
+            Some(_) => ShutdownMode::Drain,
+            None => ShutdownMode::Now,
+        }),
+    },
+    CommandSpec {
+        name: "info",
+        min_args: 0,
+        max_args: None,
+        args: &[ArgSpec::Variadic(&ArgSpec::String)],
+        build: |args| Command::INFO(args.strings_from(0)),
+    },
+];
+
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum Command<'a> {
+    PING(Option<&'a [u8]>),
+    ECHO(&'a [u8]),
+    /// The value is moved out of the request, so storing it copies nothing
+    SET(&'a [u8], Vec<u8>),
+    APPEND(&'a [u8], &'a [u8]),
+    SETRANGE(&'a [u8], isize, &'a [u8]),
+    DEL(Vec<&'a [u8]>),
+    /// Source, destination and whether to replace an existing destination
+    COPY(&'a [u8], &'a [u8], bool),
+    GET(&'a [u8]),
+    GETRANGE(&'a [u8], isize, isize),
+    BITCOUNT(&'a [u8], Option<(isize, isize, BitUnit)>),
+    KEYS(&'a [u8]),
+    CLIENT(ClientCommand),
+    DEBUG(&'static str, Vec<&'a [u8]>),
+    STATS(StatsCommand),
+    MEMORY(MemoryCommand),
+    OBJECT(ObjectCommand<'a>),
+    SHUTDOWN(ShutdownMode),
+    INFO(Vec<&'a [u8]>),
+}
+
+impl<'a> Command<'a> {
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&'a [u8]> {
+        match self {
+            Command::SET(key, _)
+            | Command::APPEND(key, _)
+            | Command::SETRANGE(key, _, _)
+            | Command::GET(key)
+            | Command::GETRANGE(key, _, _)
+            | Command::BITCOUNT(key, _)
+            | Command::OBJECT(ObjectCommand::Refcount(key)) => vec![*key],
+            Command::DEL(keys) => keys.clone(),
+            Command::COPY(source, destination, _) => vec![*source, *destination],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Whether a BITCOUNT range is in bytes or bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BitUnit {
+    Byte,
+    Bit,
+}
+
+pub(crate) enum ClientCommand {
+    Reply(ReplyMode),
+}
+
+pub(crate) enum StatsCommand {
+    Prefix,
+}
+
+pub(crate) enum MemoryCommand {
+    Stats,
+}
+
+pub(crate) enum ObjectCommand<'a> {
+    Refcount(&'a [u8]),
+}
+
+pub(crate) enum ShutdownMode {
+    /// Exit as soon as the listeners are closed
+    Now,
+    /// Give connected clients up to the shutdown timeout to finish first
+    Drain,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum ReplyMode {
+    #[default]
+    On,
+    Off,
+    Skip,
+}
+
+/// Per-connection state that commands can change.
+#[derive(Default)]
+pub(crate) struct Client {
+    /// Unique for the life of the server; 0 for clients made in tests
+    id: u64,
+    reply_mode: ReplyMode,
+    /// Commands, counting the current one, whose replies are still to be
+    /// skipped because of CLIENT REPLY SKIP
+    skip_replies: u8,
+    /// Set by a command after which the connection is closed without a reply
+    closing: bool,
+}
+
+impl Client {
+    pub fn new(id: u64) -> Self {
+        Self { id, ..Self::default() }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Called once for every request after it has been handled: whether its
+    /// reply should be sent.
+    pub fn should_reply(&mut self) -> bool {
+        if self.skip_replies > 0 {
+            self.skip_replies -= 1;
+            return false;
+        }
+        self.reply_mode != ReplyMode::Off
+    }
+
+    pub fn closing(&self) -> bool {
+        self.closing
+    }
+
+    pub(crate) fn set_reply_mode(&mut self, mode: ReplyMode) {
+        match mode {
+            // SKIP silences its own reply and the next one
+            ReplyMode::Skip => self.skip_replies = 2,
+            _ => {
+                self.reply_mode = mode;
+                self.skip_replies = 0;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum CommandParseError {
+    #[error("The message format is invalid: {0}")]
+    InvalidMessageFormat(String),
+
+    #[error("Unkown command: {0}")]
+    InvalidCommand(String),
+
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+}
+
+/// The lowercased command name of a request, if it has one.
+pub(crate) fn command_name(message: &Message) -> Option<String> {
+    message
+        .as_array()?
+        .first()?
+        .as_bulk_string()
+        .map(|name| String::from_utf8_lossy(name).to_lowercase())
+}
+
+/// Parses a request into a command. Arguments the command keeps, such as
+/// SET's value, are moved out of `message`.
+pub(crate) fn parse_command(message: &mut Message) -> Result<Command<'_>, CommandParseError> {
+    // A lot of error handling to do here...
+    let messages = message
+        .as_array()
+        .ok_or_else(|| CommandParseError::InvalidMessageFormat(message.to_json().to_string()))?;
+
+    let command = messages
+        .first()
+        .ok_or_else(|| CommandParseError::InvalidMessageFormat(message.to_json().to_string()))?
+        .as_bulk_string()
+        .ok_or_else(|| CommandParseError::InvalidCommand(message.to_json().to_string()))?;
+
+    let name = String::from_utf8_lossy(command).to_lowercase();
+    let spec = COMMANDS
+        .iter()
+        .find(|spec| spec.name == name)
+        .ok_or(CommandParseError::InvalidCommand(name))?;
+
+    let messages = message.as_array_mut().expect("checked to be an array");
+    let mut arguments = spec.validate(&mut messages[1..])?;
+    Ok((spec.build)(&mut arguments))
+}
+
+const STRING_TOO_LONG: &str = "ERR string exceeds maximum allowed size (max-bulk-len)";
+
+/// How many items long-running commands process between budget checks
+const BUDGET_CHECK_INTERVAL: usize = 1024;
+
+/// Whether a command started at `start` has used up `budget`. A zero
+/// budget never runs out.
+pub(crate) fn over_budget(start: Instant, budget: Duration) -> bool {
+    !budget.is_zero() && start.elapsed() > budget
+}
+
+/// Runs a command. Long scans give up with an error once they have run for
+/// longer than `budget`, normally the configured command budget.
+pub(crate) fn handle_command(command: &mut Command, db: &DB, client: &mut Client, budget: Duration) -> Message {
+    match execute_command(command, db, client, budget) {
+        Ok(message) => message,
+        Err(e) => Message::Error(format!("ERR {}", e)),
+    }
+}
+
+fn execute_command(
+    command: &mut Command,
+    db: &DB,
+    client: &mut Client,
+    budget: Duration,
+) -> Result<Message, StorageError> {
+    let message = match command {
+        Command::PING(None) => Message::BulkString(Some(b"PONG".to_vec())),
+        Command::PING(Some(string)) => Message::BulkString(Some(string.to_vec())),
+        Command::ECHO(string) => Message::BulkString(Some(string.to_vec())),
+        Command::SET(key, value) => {
+            db.set(key, mem::take(value))?;
+            Message::BulkString(Some(b"OK".to_vec()))
+        },
+        Command::APPEND(key, value) => {
+            let max_prealloc = config::get().max_prealloc;
+            let max_len = config::get().max_bulk_len;
+            let mut len = 0;
+            db.update(key, &mut |current| {
+                len = current.len() + value.len();
+                if len <= max_len {
+                    reserve_growth(current, len, max_prealloc);
+                    current.extend_from_slice(value);
+                }
+            })?;
+            if len > max_len {
+                return Ok(Message::Error(STRING_TOO_LONG.to_string()));
+            }
+            Message::Integer(len as isize)
+        }
+        Command::SETRANGE(key, offset, value) => {
+            let Ok(offset) = usize::try_from(*offset) else {
+                return Ok(Message::Error("ERR offset is out of range".to_string()));
+            };
+            let end = offset.saturating_add(value.len());
+            if end > config::get().max_bulk_len {
+                return Ok(Message::Error(STRING_TOO_LONG.to_string()));
+            }
+            // An empty value changes nothing, and doesn't create the key
+            if value.is_empty() {
+                let len = db.get(key)?.map_or(0, |value| value.len());
+                return Ok(Message::Integer(len as isize));
+            }
+            let max_prealloc = config::get().max_prealloc;
+            let mut len = 0;
+            db.update(key, &mut |current| {
+                if end > current.len() {
+                    reserve_growth(current, end, max_prealloc);
+                    current.resize(end, 0);
+                }
+                current[offset..end].copy_from_slice(value);
+                len = current.len();
+            })?;
+            Message::Integer(len as isize)
+        }
+        Command::DEL(keys) => {
+            let mut deleted = 0;
+            for key in keys {
+                deleted += isize::from(db.del(key)?);
+            }
+            Message::Integer(deleted)
+        }
+        Command::COPY(source, destination, replace) => {
+            if source == destination {
+                return Ok(Message::Error("ERR source and destination objects are the same".to_string()));
+            }
+            let copied = db.copy(source, destination, *replace)?;
+            Message::Integer(copied.into())
+        }
+        Command::GET(key) => {
+            match db.get(key)? {
+                Some(value) => Message::BulkString(Some(Arc::unwrap_or_clone(value))),
+                None => Message::BulkString(None),
+            }
+        }
+        Command::GETRANGE(key, start, end) => {
+            let substring = db.get(key)?.and_then(|value| {
+                normalise_string_range(*start, *end, value.len()).map(|range| value[range].to_vec())
+            });
+            Message::BulkString(Some(substring.unwrap_or_default()))
+        }
+        Command::BITCOUNT(key, range) => {
+            let count = db.get(key)?.map_or(0, |value| match *range {
+                None => popcount(&value),
+                Some((start, end, BitUnit::Byte)) => {
+                    normalise_string_range(start, end, value.len()).map_or(0, |range| popcount(&value[range]))
+                }
+                Some((start, end, BitUnit::Bit)) => {
+                    normalise_string_range(start, end, value.len() * 8).map_or(0, |bits| popcount_bits(&value, bits))
+                }
+            });
+            Message::Integer(count as isize)
+        }
+        Command::KEYS(pattern) => {
+            // Redis skips matching entirely for the common `KEYS *`
+            let match_all = *pattern == b"*";
+            let start = Instant::now();
+            let mut visited = 0usize;
+            let mut aborted = false;
+            let mut keys = Vec::new();
+            db.scan(&mut |key| {
+                visited += 1;
+                if visited.is_multiple_of(BUDGET_CHECK_INTERVAL) && over_budget(start, budget) {
+                    aborted = true;
+                    return ControlFlow::Break(());
+                }
+                if match_all || glob_match(pattern, key) {
+                    keys.push(Message::BulkString(Some(key.to_vec())));
+                }
+                ControlFlow::Continue(())
+            })?;
+            if aborted {
+                return Ok(Message::Error(
+                    "ERR KEYS aborted after exceeding the command time budget".to_string()
+                ));
+            }
+            Message::Array(Some(keys))
+        }
+        Command::CLIENT(ClientCommand::Reply(mode)) => {
+            client.set_reply_mode(*mode);
+            Message::SimpleString("OK".to_string())
+        }
+        Command::DEBUG(subcommand, args) => debug::execute(db, subcommand, args, budget)?,
+        Command::STATS(StatsCommand::Prefix) => prefix_stats(db, budget)?,
+        Command::MEMORY(MemoryCommand::Stats) => memory_stats(),
+        Command::OBJECT(ObjectCommand::Refcount(key)) => match db.refcount(key)? {
+            Some(count) => Message::Integer(count as isize),
+            None => Message::BulkString(None),
+        },
+        Command::SHUTDOWN(mode) => {
+            let grace = match mode {
+                ShutdownMode::Now => Duration::ZERO,
+                ShutdownMode::Drain => config::get().shutdown_timeout,
+            };
+            if !SHUTDOWN.begin(grace) {
+                return Ok(Message::Error("ERR shutdown already in progress".to_string()));
+            }
+            // Like Redis, a plain SHUTDOWN gets no reply: the connection
+            // closes, and the server exits once it has stopped every client
+            // and flushed the dataset
+            if let ShutdownMode::Now = mode {
+                client.closing = true;
+            }
+            Message::SimpleString("OK".to_string())
+        }
+        Command::INFO(sections) => Message::BulkString(Some(STATS.info(sections).into_bytes())),
+    };
+    Ok(message)
+}
+
+/// Replies to MEMORY STATS with the bytes allocated overall and, for each
+/// subsystem, the bytes it has allocated now and how many allocations it has
+/// made, as a flat array of names and values.
+fn memory_stats() -> Message {
+    let Some(stats) = allocator::stats() else {
+        return Message::Error("ERR MEMORY STATS needs a build with the alloc-tracking feature".to_string());
+    };
+    let field = |name: String| Message::BulkString(Some(name.into_bytes()));
+    let total: usize = stats.iter().map(|stats| stats.allocated).sum();
+    let mut reply = vec![field("total.allocated".to_string()), Message::Integer(total as isize)];
+    for stats in stats {
+        let name = stats.subsystem.name();
+        reply.push(field(format!("{}.allocated", name)));
+        reply.push(Message::Integer(stats.allocated as isize));
+        reply.push(field(format!("{}.allocations", name)));
+        reply.push(Message::Integer(stats.allocations as isize));
+    }
+    Message::Array(Some(reply))
+}
+
+/// Replies to STATS PREFIX with a flat array of fields for each configured
+/// pattern: how many keys match it, how many bytes their keys and values
+/// take up, and how many commands have used them.
+fn prefix_stats(db: &DB, budget: Duration) -> Result<Message, StorageError> {
+    let prefixes = STATS.prefix_ops();
+    let start = Instant::now();
+    let mut visited = 0usize;
+    let mut aborted = false;
+    // Matching keys with the prefixes they match. Values are read after the
+    // scan so no storage lock is held while reading them.
+    let mut matches = Vec::new();
+    db.scan(&mut |key| {
+        visited += 1;
+        if visited.is_multiple_of(BUDGET_CHECK_INTERVAL) && over_budget(start, budget) {
+            aborted = true;
+            return ControlFlow::Break(());
+        }
+        let matched: Vec<usize> = prefixes
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| glob_match(prefix.pattern.as_bytes(), key))
+            .map(|(i, _)| i)
+            .collect();
+        if !matched.is_empty() {
+            matches.push((key.to_vec(), matched));
+        }
+        ControlFlow::Continue(())
+    })?;
+    if aborted {
+        return Ok(Message::Error(
+            "ERR STATS PREFIX aborted after exceeding the command time budget".to_string()
+        ));
+    }
+
+    let mut keys = vec![0; prefixes.len()];
+    let mut memory = vec![0; prefixes.len()];
+    for (key, matched) in matches {
+        // The key may have gone since the scan
+        if let Some(value) = db.get(&key)? {
+            for i in matched {
+                keys[i] += 1;
+                memory[i] += key.len() + value.len();
+            }
+        }
+    }
+
+    let field = |name: &str| Message::BulkString(Some(name.into()));
+    let reply = prefixes
+        .iter()
+        .enumerate()
+        .map(|(i, prefix)| {
+            Message::Array(Some(vec![
+                field("pattern"),
+                field(prefix.pattern),
+                field("keys"),
+                Message::Integer(keys[i]),
+                field("memory"),
+                Message::Integer(memory[i] as isize),
+                field("ops"),
+                Message::Integer(prefix.ops as isize),
+                field("ops_per_sec"),
+                Message::Integer(prefix.ops_per_sec as isize),
+            ]))
+        })
+        .collect();
+    Ok(Message::Array(Some(reply)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::parse_message;
+    use crate::storage::MemoryStorage;
+
+    /// Sends `args` through the RESP parser as a client would, and runs them
+    fn run(db: &DB, args: &[&[u8]]) -> Message {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        let (_, mut message) = parse_message(&request).unwrap();
+        let mut command = parse_command(&mut message).unwrap();
+        handle_command(&mut command, db, &mut Client::default(), Duration::ZERO)
+    }
+
+    #[test]
+    fn test_set_takes_value_from_request() {
+        let value = vec![b'x'; 1 << 20];
+        let payload = value.as_ptr();
+        let bulk = |string: &[u8]| Message::BulkString(Some(string.to_vec()));
+        let mut message = Message::Array(Some(vec![bulk(b"SET"), bulk(b"key"), Message::BulkString(Some(value))]));
+        let Ok(Command::SET(key, value)) = parse_command(&mut message) else {
+            panic!("expected a SET");
+        };
+        assert_eq!(key, b"key");
+        assert_eq!(value.as_ptr(), payload);
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        assert_eq!(run(&db, &[b"SET", b"bm", b"\xff\x80"]), Message::BulkString(Some(b"OK".to_vec())));
+        assert_eq!(run(&db, &[b"GET", b"bm"]), Message::BulkString(Some(b"\xff\x80".to_vec())));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"bm"]), Message::Integer(9));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"bm", b"1", b"1"]), Message::Integer(1));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"bm", b"0", b"8", b"BIT"]), Message::Integer(9));
+
+        assert_eq!(run(&db, &[b"APPEND", b"bm", b"\x00\xfe"]), Message::Integer(4));
+        assert_eq!(run(&db, &[b"GETRANGE", b"bm", b"1", b"-1"]), Message::BulkString(Some(b"\x80\x00\xfe".to_vec())));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"bm"]), Message::Integer(16));
+    }
+
+    #[test]
+    fn test_append() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        // A missing key is created
+        assert_eq!(run(&db, &[b"APPEND", b"log", b"abc"]), Message::Integer(3));
+        assert_eq!(run(&db, &[b"APPEND", b"log", b"de"]), Message::Integer(5));
+        assert_eq!(run(&db, &[b"APPEND", b"log", b""]), Message::Integer(5));
+        assert_eq!(run(&db, &[b"GET", b"log"]), Message::BulkString(Some(b"abcde".to_vec())));
+    }
+
+    #[test]
+    fn test_setrange() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        // Writing past the end pads with zero bytes
+        assert_eq!(run(&db, &[b"SETRANGE", b"key", b"3", b"ab"]), Message::Integer(5));
+        assert_eq!(run(&db, &[b"GET", b"key"]), Message::BulkString(Some(b"\0\0\0ab".to_vec())));
+        assert_eq!(run(&db, &[b"SETRANGE", b"key", b"1", b"x"]), Message::Integer(5));
+        assert_eq!(run(&db, &[b"GET", b"key"]), Message::BulkString(Some(b"\0x\0ab".to_vec())));
+
+        // An empty value reports the length without creating the key
+        assert_eq!(run(&db, &[b"SETRANGE", b"key", b"100", b""]), Message::Integer(5));
+        assert_eq!(run(&db, &[b"SETRANGE", b"missing", b"0", b""]), Message::Integer(0));
+        assert_eq!(run(&db, &[b"GET", b"missing"]), Message::BulkString(None));
+
+        assert_eq!(
+            run(&db, &[b"SETRANGE", b"key", b"-1", b"x"]),
+            Message::Error("ERR offset is out of range".to_string())
+        );
+        let max_len = config::get().max_bulk_len.to_string();
+        assert_eq!(run(&db, &[b"SETRANGE", b"key", max_len.as_bytes(), b"x"]), Message::Error(STRING_TOO_LONG.to_string()));
+        assert_eq!(run(&db, &[b"GET", b"key"]), Message::BulkString(Some(b"\0x\0ab".to_vec())));
+    }
+
+    #[test]
+    fn test_string_ranges() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        run(&db, &[b"SET", b"key", b"\xffhello"]);
+        // As in Redis 7.2, an end before the start of the string is clamped
+        // to its first byte
+        assert_eq!(run(&db, &[b"GETRANGE", b"key", b"0", b"-100"]), Message::BulkString(Some(b"\xff".to_vec())));
+        assert_eq!(run(&db, &[b"SUBSTR", b"key", b"-100", b"-50"]), Message::BulkString(Some(b"\xff".to_vec())));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"key", b"0", b"-100"]), Message::Integer(8));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"key", b"0", b"-100", b"BIT"]), Message::Integer(1));
+        // Two negative indexes out of order still select nothing
+        assert_eq!(run(&db, &[b"GETRANGE", b"key", b"-1", b"-2"]), Message::BulkString(Some(Vec::new())));
+        assert_eq!(run(&db, &[b"BITCOUNT", b"key", b"-1", b"-2"]), Message::Integer(0));
+    }
+
+    #[test]
+    fn test_copy_onto_itself() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        db.set(b"a", b"1".to_vec()).unwrap();
+        let mut client = Client::default();
+        for replace in [false, true] {
+            assert_eq!(
+                handle_command(&mut Command::COPY(b"a", b"a", replace), &db, &mut client, Duration::ZERO),
+                Message::Error("ERR source and destination objects are the same".to_string())
+            );
+        }
+        assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_object_refcount() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        run(&db, &[b"SET", b"a", b"value"]);
+        assert_eq!(run(&db, &[b"OBJECT", b"REFCOUNT", b"a"]), Message::Integer(1));
+        run(&db, &[b"COPY", b"a", b"b"]);
+        assert_eq!(run(&db, &[b"OBJECT", b"refcount", b"b"]), Message::Integer(2));
+        // Writing to either key gives it its own copy
+        run(&db, &[b"APPEND", b"a", b"!"]);
+        assert_eq!(run(&db, &[b"OBJECT", b"REFCOUNT", b"a"]), Message::Integer(1));
+        assert_eq!(run(&db, &[b"OBJECT", b"REFCOUNT", b"b"]), Message::Integer(1));
+        assert_eq!(run(&db, &[b"OBJECT", b"REFCOUNT", b"missing"]), Message::BulkString(None));
+    }
+
+    #[test]
+    fn test_over_budget() {
+        let start = Instant::now() - Duration::from_millis(10);
+        assert!(over_budget(start, Duration::from_millis(1)));
+        assert!(!over_budget(start, Duration::from_secs(60)));
+        // A zero budget turns the limit off
+        assert!(!over_budget(start, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_scans_abort_over_budget() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        for i in 0..2 * BUDGET_CHECK_INTERVAL {
+            db.set(format!("key:{}", i).as_bytes(), Vec::new()).unwrap();
+        }
+        let mut client = Client::default();
+        let mut run = |command: &mut Command, budget| handle_command(command, &db, &mut client, budget);
+
+        assert_eq!(
+            run(&mut Command::KEYS(b"*"), Duration::from_nanos(1)),
+            Message::Error("ERR KEYS aborted after exceeding the command time budget".to_string())
+        );
+        assert_eq!(
+            run(&mut Command::STATS(StatsCommand::Prefix), Duration::from_nanos(1)),
+            Message::Error("ERR STATS PREFIX aborted after exceeding the command time budget".to_string())
+        );
+        let Message::Array(Some(keys)) = run(&mut Command::KEYS(b"*"), Duration::ZERO) else {
+            panic!("expected an array");
+        };
+        assert_eq!(keys.len(), 2 * BUDGET_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn test_client_reply_modes() {
+        let mut client = Client::default();
+        assert!(client.should_reply());
+
+        client.set_reply_mode(ReplyMode::Off);
+        assert!(!client.should_reply());
+        assert!(!client.should_reply());
+
+        client.set_reply_mode(ReplyMode::On);
+        assert!(client.should_reply());
+
+        // SKIP drops its own reply and the next command's, then replies resume
+        client.set_reply_mode(ReplyMode::Skip);
+        assert!(!client.should_reply());
+        assert!(!client.should_reply());
+        assert!(client.should_reply());
+
+        // SKIP while replies are off leaves them off
+        client.set_reply_mode(ReplyMode::Off);
+        client.set_reply_mode(ReplyMode::Skip);
+        assert!(!client.should_reply());
+        assert!(!client.should_reply());
+        assert!(!client.should_reply());
+    }
+}
@@ -0,0 +1,253 @@
+use std::{mem, slice, str};
+
+use super::{BitUnit, Command, CommandParseError};
+use crate::message::Message;
+
+/// Declarative description of a command's arguments. `parse_command` checks
+/// a request against its spec before `build` ever sees the arguments, so
+/// builders only have to pick validated values out of `Args`.
+pub(crate) struct CommandSpec {
+    pub name: &'static str,
+    pub min_args: usize,
+    /// `None` for variadic commands
+    pub max_args: Option<usize>,
+    pub args: &'static [ArgSpec],
+    pub build: for<'a> fn(&mut Args<'a>) -> Command<'a>,
+}
+
+pub(crate) enum ArgSpec {
+    /// A binary safe string, borrowed from the request
+    String,
+    /// A string the command keeps, such as the value of a SET. It is moved
+    /// out of the request rather than copied.
+    Value,
+    Integer,
+    /// One of a fixed set of lowercase keywords, matched case-insensitively
+    Token(&'static [&'static str]),
+    /// A block that is either given in full or left out entirely
+    Optional(&'static [ArgSpec]),
+    /// Zero or more repetitions, taking every remaining argument
+    Variadic(&'static ArgSpec),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Arg<'a> {
+    String(&'a [u8]),
+    Value(Vec<u8>),
+    Integer(isize),
+    Token(&'static str),
+}
+
+/// Arguments that passed validation, in the order they were given.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Args<'a>(Vec<Arg<'a>>);
+
+impl<'a> Args<'a> {
+    pub fn string(&self, i: usize) -> &'a [u8] {
+        self.optional_string(i).expect("string argument checked by spec")
+    }
+
+    pub fn optional_string(&self, i: usize) -> Option<&'a [u8]> {
+        match self.0.get(i) {
+            Some(Arg::String(string)) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// Takes a `Value` argument. It can only be taken once.
+    pub fn value(&mut self, i: usize) -> Vec<u8> {
+        match self.0.get_mut(i) {
+            Some(Arg::Value(value)) => mem::take(value),
+            _ => panic!("value argument checked by spec"),
+        }
+    }
+
+    pub fn integer(&self, i: usize) -> isize {
+        match self.0.get(i) {
+            Some(Arg::Integer(n)) => *n,
+            _ => panic!("integer argument checked by spec"),
+        }
+    }
+
+    pub fn token(&self, i: usize) -> &'static str {
+        self.optional_token(i).expect("token argument checked by spec")
+    }
+
+    pub fn optional_token(&self, i: usize) -> Option<&'static str> {
+        match self.0.get(i) {
+            Some(Arg::Token(token)) => Some(token),
+            _ => None,
+        }
+    }
+
+    /// An optional `start end [BYTE | BIT]` block starting at `i`.
+    pub fn optional_range(&self, i: usize) -> Option<(isize, isize, BitUnit)> {
+        self.0.get(i)?;
+        let unit = match self.0.get(i + 2) {
+            Some(Arg::Token("bit")) => BitUnit::Bit,
+            _ => BitUnit::Byte,
+        };
+        Some((self.integer(i), self.integer(i + 1), unit))
+    }
+
+    pub fn strings_from(&self, i: usize) -> Vec<&'a [u8]> {
+        (i..self.0.len()).map(|i| self.string(i)).collect()
+    }
+}
+
+impl CommandSpec {
+    /// Checks `arguments` (everything after the command name) against the
+    /// spec and converts them into typed `Args`. `Value` arguments are moved
+    /// out of `arguments`, even if a later one turns out to be invalid.
+    pub fn validate<'a>(&self, arguments: &'a mut [Message]) -> Result<Args<'a>, CommandParseError> {
+        let wrong_arity = || {
+            CommandParseError::InvalidArguments(format!(
+                "Wrong number of arguments for the {} command",
+                self.name.to_uppercase()
+            ))
+        };
+        if arguments.len() < self.min_args || self.max_args.is_some_and(|max| arguments.len() > max) {
+            return Err(wrong_arity());
+        }
+
+        let mut args = Vec::with_capacity(arguments.len());
+        let remaining = match_args(self.args, arguments, &mut args)?.ok_or_else(wrong_arity)?;
+        if !remaining.is_empty() {
+            return Err(wrong_arity());
+        }
+        Ok(Args(args))
+    }
+}
+
+/// Matches `specs` against the front of `arguments`, returning what is left
+/// over, or `None` if there were too few arguments.
+fn match_args<'a>(
+    specs: &[ArgSpec],
+    mut arguments: &'a mut [Message],
+    args: &mut Vec<Arg<'a>>,
+) -> Result<Option<&'a mut [Message]>, CommandParseError> {
+    for spec in specs {
+        match spec {
+            ArgSpec::String | ArgSpec::Value | ArgSpec::Integer | ArgSpec::Token(_) => {
+                let Some((argument, rest)) = arguments.split_first_mut() else {
+                    return Ok(None);
+                };
+                args.push(parse_arg(spec, argument)?);
+                arguments = rest;
+            }
+            ArgSpec::Optional(block) => {
+                if !arguments.is_empty() {
+                    match match_args(block, arguments, args)? {
+                        Some(rest) => arguments = rest,
+                        None => return Ok(None),
+                    }
+                }
+            }
+            ArgSpec::Variadic(spec) => {
+                while !arguments.is_empty() {
+                    match match_args(slice::from_ref(*spec), arguments, args)? {
+                        Some(rest) => arguments = rest,
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+    Ok(Some(arguments))
+}
+
+fn parse_arg<'a>(spec: &ArgSpec, argument: &'a mut Message) -> Result<Arg<'a>, CommandParseError> {
+    let not_bulk = || CommandParseError::InvalidArguments("Argument not a BulkString".to_string());
+    if let ArgSpec::Value = spec {
+        let Message::BulkString(Some(value)) = argument else {
+            return Err(not_bulk());
+        };
+        return Ok(Arg::Value(mem::take(value)));
+    }
+    let string = argument.as_bulk_string().ok_or_else(not_bulk)?;
+    match spec {
+        ArgSpec::Integer => str::from_utf8(string)
+            .ok()
+            .and_then(|string| string.parse().ok())
+            .map(Arg::Integer)
+            .ok_or_else(|| {
+                CommandParseError::InvalidArguments(
+                    "value is not an integer or out of range".to_string()
+                )
+            }),
+        ArgSpec::Token(tokens) => tokens
+            .iter()
+            .find(|token| token.as_bytes().eq_ignore_ascii_case(string))
+            .map(|token| Arg::Token(token))
+            .ok_or(CommandParseError::InvalidArguments("syntax error".to_string())),
+        _ => Ok(Arg::String(string)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn arguments(args: &[&str]) -> Vec<Message> {
+        args.iter().map(|arg| Message::BulkString(Some(arg.as_bytes().to_vec()))).collect()
+    }
+
+    fn spec(min_args: usize, max_args: Option<usize>, args: &'static [ArgSpec]) -> CommandSpec {
+        CommandSpec { name: "test", min_args, max_args, args, build: |_| Command::PING(None) }
+    }
+
+    #[test]
+    fn test_fixed_arguments() {
+        let spec = spec(2, Some(2), &[ArgSpec::String, ArgSpec::Integer]);
+        assert_eq!(
+            spec.validate(&mut arguments(&["key", "-3"])).unwrap(),
+            Args(vec![Arg::String(b"key"), Arg::Integer(-3)])
+        );
+        assert!(spec.validate(&mut arguments(&["key"])).is_err());
+        assert!(spec.validate(&mut arguments(&["key", "1", "2"])).is_err());
+        assert!(spec.validate(&mut arguments(&["key", "one"])).is_err());
+        assert!(spec.validate(&mut [Message::Integer(1), Message::Integer(2)]).is_err());
+    }
+
+    #[test]
+    fn test_optional_block() {
+        const ARGS: &[ArgSpec] = &[ArgSpec::String, ArgSpec::Optional(&[ArgSpec::Integer, ArgSpec::Integer])];
+        let spec = spec(1, Some(3), ARGS);
+        assert_eq!(spec.validate(&mut arguments(&["key"])).unwrap(), Args(vec![Arg::String(b"key")]));
+        assert_eq!(
+            spec.validate(&mut arguments(&["key", "0", "-1"])).unwrap(),
+            Args(vec![Arg::String(b"key"), Arg::Integer(0), Arg::Integer(-1)])
+        );
+        // Blocks are all or nothing
+        assert!(spec.validate(&mut arguments(&["key", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_values_are_moved_out() {
+        let spec = spec(2, Some(2), &[ArgSpec::String, ArgSpec::Value]);
+        let mut given = arguments(&["key", "value"]);
+        let mut args = spec.validate(&mut given).unwrap();
+        assert_eq!((args.string(0), args.value(1)), (&b"key"[..], b"value".to_vec()));
+        assert_eq!(given[1], Message::BulkString(Some(Vec::new())));
+        assert!(spec.validate(&mut [Message::BulkString(Some(b"key".to_vec())), Message::Integer(1)]).is_err());
+    }
+
+    #[test]
+    fn test_tokens() {
+        const ARGS: &[ArgSpec] = &[ArgSpec::Token(&["reply"]), ArgSpec::Token(&["on", "off"])];
+        let spec = spec(2, Some(2), ARGS);
+        let mut given = arguments(&["REPLY", "Off"]);
+        let args = spec.validate(&mut given).unwrap();
+        assert_eq!((args.token(0), args.token(1)), ("reply", "off"));
+        assert!(spec.validate(&mut arguments(&["reply", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_variadic() {
+        const ARGS: &[ArgSpec] = &[ArgSpec::Variadic(&ArgSpec::String)];
+        let spec = spec(0, None, ARGS);
+        assert!(spec.validate(&mut arguments(&[])).unwrap().strings_from(0).is_empty());
+        let mut given = arguments(&["a", "b", "c"]);
+        assert_eq!(spec.validate(&mut given).unwrap().strings_from(1), vec![b"b", b"c"]);
+    }
+}
@@ -0,0 +1,352 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::util::parse_cpu_list;
+
+const DEFAULT_IP: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "6379";
+const DEFAULT_DIR: &str = "./redirs-data";
+const DEFAULT_COMMAND_BUDGET: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_NESTING_DEPTH: usize = 128;
+const DEFAULT_MAX_ARRAY_LEN: usize = 1024 * 1024;
+const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+const DEFAULT_CLIENT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+const DEFAULT_MAX_PREALLOC: usize = 1024 * 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StorageEngine {
+    Memory,
+    Sled,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Config {
+    pub ip: String,
+    /// TCP port to listen on; "0" disables TCP
+    pub port: String,
+    /// Unix domain socket to listen on as well as, or instead of, TCP
+    pub unix_socket: Option<PathBuf>,
+    pub storage_engine: StorageEngine,
+    /// Where disk-backed engines keep their files
+    pub dir: PathBuf,
+    /// Writes the disk-backed engine may queue before writers block.
+    /// 0 writes through to disk synchronously.
+    pub write_behind_backlog: usize,
+    /// How long one command may run before it is logged as slow and long
+    /// scans give up. Zero disables the budget.
+    pub command_budget: Duration,
+    /// How deeply arrays may nest in one message before it is rejected as a
+    /// protocol error. At least 1.
+    pub max_nesting_depth: usize,
+    /// The most elements an array header may announce
+    pub max_array_len: usize,
+    /// The longest bulk string a header may announce, in bytes
+    pub max_bulk_len: usize,
+    /// The most arguments one command may have
+    pub max_multibulk_len: usize,
+    /// How many bytes one connection's pending requests may take up
+    pub client_query_buffer_limit: usize,
+    /// The most spare bytes reserved when a string value grows
+    pub max_prealloc: usize,
+    /// Key patterns, e.g. `tenant:*`, that STATS PREFIX reports on
+    pub stats_prefixes: Vec<String>,
+    /// How long SHUTDOWN DRAIN waits for clients to disconnect
+    pub shutdown_timeout: Duration,
+    /// CPUs for the threads serving clients; empty leaves them unpinned
+    pub server_cpulist: Vec<usize>,
+    /// CPUs for background threads such as the write-behind writer
+    pub bio_cpulist: Vec<usize>,
+    /// OTLP/HTTP traces URL to export command spans to
+    pub otlp_endpoint: Option<String>,
+    /// File to record every inbound frame to
+    pub record: Option<PathBuf>,
+    /// Recording to replay against the server at `ip`:`port`, instead of
+    /// running a server
+    pub replay: Option<PathBuf>,
+    /// How much faster than recorded to replay; 0 for no gaps at all
+    pub replay_speed: f64,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum ConfigError {
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+
+    #[error("Missing value for option: {0}")]
+    MissingValue(String),
+
+    #[error("Invalid value for option {0}: {1}")]
+    InvalidValue(String, String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ip: DEFAULT_IP.to_string(),
+            port: DEFAULT_PORT.to_string(),
+            unix_socket: None,
+            storage_engine: StorageEngine::Memory,
+            dir: PathBuf::from(DEFAULT_DIR),
+            write_behind_backlog: 0,
+            command_budget: DEFAULT_COMMAND_BUDGET,
+            max_nesting_depth: DEFAULT_MAX_NESTING_DEPTH,
+            max_array_len: DEFAULT_MAX_ARRAY_LEN,
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN,
+            client_query_buffer_limit: DEFAULT_CLIENT_QUERY_BUFFER_LIMIT,
+            max_prealloc: DEFAULT_MAX_PREALLOC,
+            stats_prefixes: Vec::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            server_cpulist: Vec::new(),
+            bio_cpulist: Vec::new(),
+            otlp_endpoint: None,
+            record: None,
+            replay: None,
+            replay_speed: 1.0,
+        }
+    }
+}
+
+impl Config {
+    /// Builds a config from `--option value` pairs, e.g. the process arguments.
+    pub fn from_args<I>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| ConfigError::MissingValue(option.clone()))?;
+            match option.as_str() {
+                "--bind" => config.ip = value,
+                "--port" => config.port = value,
+                "--unixsocket" => config.unix_socket = Some(PathBuf::from(value)),
+                "--storage-engine" => {
+                    config.storage_engine = match value.to_lowercase().as_str() {
+                        "memory" => StorageEngine::Memory,
+                        "sled" => StorageEngine::Sled,
+                        _ => return Err(ConfigError::InvalidValue(option, value)),
+                    }
+                }
+                "--dir" => config.dir = PathBuf::from(value),
+                "--write-behind-backlog" => {
+                    config.write_behind_backlog = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--command-budget-ms" => {
+                    config.command_budget = value
+                        .parse()
+                        .map(Duration::from_millis)
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--max-nesting-depth" => {
+                    config.max_nesting_depth = value
+                        .parse()
+                        .ok()
+                        .filter(|depth| *depth > 0)
+                        .ok_or(ConfigError::InvalidValue(option, value))?
+                }
+                "--max-array-len" => {
+                    config.max_array_len = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--max-bulk-len" => {
+                    config.max_bulk_len = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--max-multibulk-len" => {
+                    config.max_multibulk_len = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--client-query-buffer-limit" => {
+                    config.client_query_buffer_limit = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--max-prealloc" => {
+                    config.max_prealloc = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--shutdown-timeout" => {
+                    config.shutdown_timeout = value
+                        .parse()
+                        .map(Duration::from_secs)
+                        .map_err(|_| ConfigError::InvalidValue(option, value))?
+                }
+                "--server-cpulist" => {
+                    config.server_cpulist =
+                        parse_cpu_list(&value).ok_or(ConfigError::InvalidValue(option, value))?
+                }
+                "--bio-cpulist" => {
+                    config.bio_cpulist =
+                        parse_cpu_list(&value).ok_or(ConfigError::InvalidValue(option, value))?
+                }
+                "--otlp-endpoint" => config.otlp_endpoint = Some(value),
+                "--record" => config.record = Some(PathBuf::from(value)),
+                "--replay" => config.replay = Some(PathBuf::from(value)),
+                "--replay-speed" => {
+                    config.replay_speed = value
+                        .parse()
+                        .ok()
+                        .filter(|speed: &f64| speed.is_finite() && *speed >= 0.0)
+                        .ok_or(ConfigError::InvalidValue(option, value))?
+                }
+                // May be given more than once
+                "--stats-prefix" => config.stats_prefixes.push(value),
+                _ => return Err(ConfigError::UnknownOption(option)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Installs the server-wide config read through `get`. Only the first call
+/// has any effect.
+pub(crate) fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The server-wide config, or the defaults if none was installed.
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::from_args(args(&[])), Ok(Config::default()));
+    }
+
+    #[test]
+    fn test_storage_engine() {
+        let config = Config::from_args(args(&["--storage-engine", "sled", "--dir", "/tmp/db"])).unwrap();
+        assert_eq!(config.storage_engine, StorageEngine::Sled);
+        assert_eq!(config.dir, PathBuf::from("/tmp/db"));
+
+        let config = Config::from_args(args(&["--write-behind-backlog", "1024"])).unwrap();
+        assert_eq!(config.write_behind_backlog, 1024);
+
+        assert_eq!(
+            Config::from_args(args(&["--storage-engine", "floppy"])),
+            Err(ConfigError::InvalidValue("--storage-engine".to_string(), "floppy".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_protocol_limits() {
+        let config = Config::from_args(args(&["--max-nesting-depth", "8"])).unwrap();
+        assert_eq!(config.max_nesting_depth, 8);
+
+        let config = Config::from_args(args(&["--max-array-len", "16", "--max-bulk-len", "1024"])).unwrap();
+        assert_eq!((config.max_array_len, config.max_bulk_len), (16, 1024));
+
+        let config = Config::from_args(args(&["--max-multibulk-len", "3", "--client-query-buffer-limit", "4096"])).unwrap();
+        assert_eq!((config.max_multibulk_len, config.client_query_buffer_limit), (3, 4096));
+
+        assert_eq!(
+            Config::from_args(args(&["--max-nesting-depth", "0"])),
+            Err(ConfigError::InvalidValue("--max-nesting-depth".to_string(), "0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_command_budget() {
+        let config = Config::from_args(args(&["--command-budget-ms", "250"])).unwrap();
+        assert_eq!(config.command_budget, Duration::from_millis(250));
+
+        let config = Config::from_args(args(&["--command-budget-ms", "0"])).unwrap();
+        assert_eq!(config.command_budget, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_max_prealloc() {
+        let config = Config::from_args(args(&["--max-prealloc", "4096"])).unwrap();
+        assert_eq!(config.max_prealloc, 4096);
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let config = Config::from_args(args(&["--port", "0", "--unixsocket", "/tmp/redirs.sock"])).unwrap();
+        assert_eq!(config.port, "0");
+        assert_eq!(config.unix_socket, Some(PathBuf::from("/tmp/redirs.sock")));
+    }
+
+    #[test]
+    fn test_stats_prefixes() {
+        let config = Config::from_args(args(&["--stats-prefix", "tenant:a:*", "--stats-prefix", "tenant:b:*"])).unwrap();
+        assert_eq!(config.stats_prefixes, ["tenant:a:*", "tenant:b:*"]);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let config = Config::from_args(args(&["--shutdown-timeout", "30"])).unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_cpu_lists() {
+        // CPU 0 is the only one every machine has
+        let config = Config::from_args(args(&["--server-cpulist", "0", "--bio-cpulist", "0,0"])).unwrap();
+        assert_eq!(config.server_cpulist, [0]);
+        assert_eq!(config.bio_cpulist, [0]);
+
+        for list in ["3-1", "0-1000000000"] {
+            assert_eq!(
+                Config::from_args(args(&["--bio-cpulist", list])),
+                Err(ConfigError::InvalidValue("--bio-cpulist".to_string(), list.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_otlp_endpoint() {
+        let config = Config::from_args(args(&["--otlp-endpoint", "http://localhost:4318/v1/traces"])).unwrap();
+        assert_eq!(config.otlp_endpoint.as_deref(), Some("http://localhost:4318/v1/traces"));
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let config = Config::from_args(args(&["--record", "/tmp/frames", "--replay", "/tmp/in", "--replay-speed", "2.5"])).unwrap();
+        assert_eq!(config.record, Some(PathBuf::from("/tmp/frames")));
+        assert_eq!(config.replay, Some(PathBuf::from("/tmp/in")));
+        assert_eq!(config.replay_speed, 2.5);
+
+        for speed in ["-1", "inf", "NaN"] {
+            assert_eq!(
+                Config::from_args(args(&["--replay-speed", speed])),
+                Err(ConfigError::InvalidValue("--replay-speed".to_string(), speed.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_bad_options() {
+        assert_eq!(
+            Config::from_args(args(&["--port"])),
+            Err(ConfigError::MissingValue("--port".to_string()))
+        );
+        assert_eq!(
+            Config::from_args(args(&["--verbose", "yes"])),
+            Err(ConfigError::UnknownOption("--verbose".to_string()))
+        );
+    }
+}
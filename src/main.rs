@@ -1,16 +1,66 @@
 
 mod message;
 mod server;
-use dashmap::DashMap;
-use server::{listen, handle_client};
+use config::Config;
+use server::listen;
+mod allocator;
 mod command;
-
-const DEFAULT_PORT: &str = "6379";
+mod config;
+mod replay;
+mod shutdown;
+mod stats;
+mod storage;
+mod telemetry;
+mod util;
 
 
 
 fn main() {
-    // let db: HashmapDB = HashmapDB::new();
-    let db = DashMap::new();
-    let _ = listen("127.0.0.1", DEFAULT_PORT, handle_client, db);
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &config.replay {
+        let address = format!("{}:{}", config.ip, config.port);
+        match replay::replay(path, &address, config.replay_speed) {
+            Ok(frames) => println!("Replayed {} frames", frames),
+            Err(e) => {
+                eprintln!("Replay failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(path) = &config.record {
+        if let Err(e) = replay::start_recording(path) {
+            eprintln!("Failed to start recording: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let db = match storage::open(config) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let _telemetry = match telemetry::init(config) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = listen(config, db.clone()) {
+        eprintln!("Failed to listen: {}", e);
+        std::process::exit(1);
+    }
+    // Shut down: make sure everything acknowledged is on disk before exiting
+    if let Err(e) = db.flush() {
+        eprintln!("Failed to persist the dataset: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,123 @@
+use serde_json::{json, Map, Number, Value};
+use thiserror::Error;
+
+use super::Message;
+
+#[derive(Debug, PartialEq, Error)]
+pub(crate) enum JsonError {
+    #[error("no message is written as {0}")]
+    Unsupported(String),
+}
+
+/// Converts messages to and from JSON for people to read.
+///
+/// The common types map onto plain JSON: bulk strings are strings (bytes
+/// that aren't UTF-8 are replaced, so binary values don't round trip), integers
+/// are numbers, arrays are arrays, booleans are booleans and RESP3 null is
+/// `null`. The rest are objects with a single key naming the type:
+/// `{"simple": "OK"}`, `{"error": "ERR ..."}`, `{"double": 1.5}` (or
+/// `"inf"`, `"-inf"`, `"NaN"`), `{"bulk": null}` and `{"array": null}`.
+impl Message {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Message::SimpleString(string) => json!({ "simple": string }),
+            Message::Error(error) => json!({ "error": error }),
+            Message::Integer(n) => json!(n),
+            Message::BulkString(Some(string)) => json!(String::from_utf8_lossy(string)),
+            Message::BulkString(None) => json!({ "bulk": null }),
+            Message::Array(Some(messages)) => Value::Array(messages.iter().map(Message::to_json).collect()),
+            Message::Array(None) => json!({ "array": null }),
+            Message::Null => Value::Null,
+            Message::Bool(b) => json!(b),
+            Message::Double(n) => match Number::from_f64(*n) {
+                Some(n) => json!({ "double": n }),
+                None => json!({ "double": n.to_string() }),
+            },
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Result<Message, JsonError> {
+        let unsupported = || JsonError::Unsupported(value.to_string());
+        let message = match value {
+            Value::String(string) => Message::BulkString(Some(string.clone().into_bytes())),
+            Value::Number(n) => Message::Integer(n.as_i64().and_then(|n| isize::try_from(n).ok()).ok_or_else(unsupported)?),
+            Value::Array(values) => {
+                Message::Array(Some(values.iter().map(Message::from_json).collect::<Result<_, _>>()?))
+            }
+            Value::Null => Message::Null,
+            Value::Bool(b) => Message::Bool(*b),
+            Value::Object(object) => tagged_from_json(object).ok_or_else(unsupported)?,
+        };
+        Ok(message)
+    }
+}
+
+fn tagged_from_json(object: &Map<String, Value>) -> Option<Message> {
+    if object.len() != 1 {
+        return None;
+    }
+    let message = match object.iter().next()? {
+        (tag, Value::String(string)) if tag == "simple" => Message::SimpleString(string.clone()),
+        (tag, Value::String(error)) if tag == "error" => Message::Error(error.clone()),
+        (tag, Value::Number(n)) if tag == "double" => Message::Double(n.as_f64()?),
+        (tag, Value::String(n)) if tag == "double" => Message::Double(n.parse().ok()?),
+        (tag, Value::Null) if tag == "bulk" => Message::BulkString(None),
+        (tag, Value::Null) if tag == "array" => Message::Array(None),
+        _ => return None,
+    };
+    Some(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let message = Message::Array(Some(vec![
+            Message::BulkString(Some(b"SET".to_vec())),
+            Message::Integer(-3),
+            Message::SimpleString("OK".to_string()),
+            Message::Error("ERR bad".to_string()),
+            Message::BulkString(None),
+            Message::Array(None),
+            Message::Null,
+            Message::Bool(true),
+            Message::Double(1.5),
+            Message::Double(f64::NEG_INFINITY),
+        ]));
+        assert_eq!(
+            message.to_json().to_string(),
+            r#"["SET",-3,{"simple":"OK"},{"error":"ERR bad"},{"bulk":null},{"array":null},null,true,{"double":1.5},{"double":"-inf"}]"#
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let messages = [
+            Message::BulkString(Some("héllo\r\n".into())),
+            Message::Integer(isize::MIN),
+            Message::SimpleString("PONG".to_string()),
+            Message::Error("ERR x".to_string()),
+            Message::BulkString(None),
+            Message::Array(None),
+            Message::Array(Some(vec![Message::Array(Some(vec![])), Message::Null])),
+            Message::Bool(false),
+            Message::Double(1.0),
+            Message::Double(f64::INFINITY),
+        ];
+        for message in messages {
+            assert_eq!(Message::from_json(&message.to_json()), Ok(message));
+        }
+        let nan = Message::from_json(&Message::Double(f64::NAN).to_json());
+        assert!(matches!(nan, Ok(Message::Double(n)) if n.is_nan()));
+    }
+
+    #[test]
+    fn test_unsupported_json() {
+        for json in [r#"1.5"#, r#"{}"#, r#"{"simple":1}"#, r#"{"bulk":null,"array":null}"#, r#"[{"map":{}}]"#] {
+            let value: Value = serde_json::from_str(json).unwrap();
+            assert!(Message::from_json(&value).is_err(), "{}", json);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use super::serialise_message;
 
 #[derive(Debug, PartialEq)]
@@ -5,7 +7,8 @@ pub(crate) enum Message {
     SimpleString(String),
     Error(String),
     Integer(isize),
-    BulkString(Option<String>),
+    /// Binary safe: the payload is kept exactly as it was sent
+    BulkString(Option<Vec<u8>>),
     Array(Option<Vec<Message>>),
     Null,
     Bool(bool),
@@ -17,11 +20,7 @@ impl Message {
         serialise_message(self)
     }
 
-    pub fn to_string(&self) -> String {
-        String::from_utf8_lossy(&self.serialise()).into()
-    }
-
-    pub fn as_bulk_string(&self) -> Option<&String> {
+    pub fn as_bulk_string(&self) -> Option<&[u8]> {
         if let Self::BulkString(Some(ref string)) = self {
             Some(string)
         } else {
@@ -36,4 +35,18 @@ impl Message {
             None
         }
     }
-}
\ No newline at end of file
+
+    pub fn as_array_mut(&mut self) -> Option<&mut [Message]> {
+        if let Self::Array(Some(ref mut messages)) = self {
+            Some(messages)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.serialise()))
+    }
+}
@@ -1,6 +1,10 @@
+#[allow(clippy::module_inception)]
 mod message;
 pub(crate) use message::Message;
+mod json;
 mod parse;
-pub(crate) use parse::parse_message;
+pub(crate) use parse::{parse_array_header, parse_message, ParseError};
+mod read;
+pub(crate) use read::read_request;
 mod serialise;
-pub(crate) use serialise::serialise_message;
\ No newline at end of file
+pub(crate) use serialise::{serialise_message, serialised_len};
\ No newline at end of file
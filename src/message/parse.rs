@@ -1,164 +1,251 @@
 use core::str;
 
-use nom::{
-    branch::alt,
-    bytes::complete::{tag, take, take_while},
-    character::complete::{crlf, digit1, one_of},
-    combinator::{map_res, opt, value},
-    error::ErrorKind,
-    multi::many_m_n,
-    number::complete::double,
-    sequence::{delimited, pair, preceded},
-    Err, IResult,
-};
+use thiserror::Error;
 
 use super::Message;
+use crate::config;
 
 const CRLF: &[u8] = b"\r\n";
 
+/// Array lengths come from the client, so only this many elements are
+/// reserved up front; longer arrays grow as their elements arrive.
+const MAX_PREALLOC: usize = 1024;
+
+#[derive(Debug, PartialEq, Error)]
+pub(crate) enum ParseError {
+    /// The input stops part way through a message; more may still arrive
+    #[error("incomplete message")]
+    Incomplete,
+
+    #[error("Protocol error: {0}")]
+    Invalid(&'static str),
+}
+
+pub(super) type ParseResult<'a, T> = Result<(&'a [u8], T), ParseError>;
+
+type Parser = for<'a> fn(&'a [u8]) -> ParseResult<'a, Message>;
+
+/// The parser for each type, indexed by its tag byte.
+const PARSERS: [Option<Parser>; 256] = {
+    let mut parsers: [Option<Parser>; 256] = [None; 256];
+    parsers[b'+' as usize] = Some(parse_simple_string);
+    parsers[b'-' as usize] = Some(parse_error);
+    parsers[b':' as usize] = Some(parse_integer);
+    parsers[b'$' as usize] = Some(parse_bulk_string);
+    parsers[b'*' as usize] = Some(parse_array);
+    parsers[b'_' as usize] = Some(parse_null);
+    parsers[b'#' as usize] = Some(parse_bool);
+    parsers[b',' as usize] = Some(parse_double);
+    parsers
+};
+
 macro_rules! check_tag {
     ($target:expr, $input:expr) => {{
         // Safely check and consume the first byte of the input
-        if let Some(&tag) = ($input).get(0) {
-            assert_eq!(tag, $target, "Expected tag {:?}, but found {:?}", $target, tag);
-            $input = &$input[1..]; // Update the input reference
-        } else {
-            panic!("Input is empty, expected tag {:?}", $target);
+        match ($input).first() {
+            Some(&tag) if tag == $target => $input = &$input[1..],
+            Some(_) => return Err(ParseError::Invalid("unexpected tag")),
+            None => return Err(ParseError::Incomplete),
         }
     }};
 }
 
+fn parse_crlf(i: &[u8]) -> Result<&[u8], ParseError> {
+    match i.strip_prefix(CRLF) {
+        Some(remaining) => Ok(remaining),
+        None if CRLF.starts_with(i) => Err(ParseError::Incomplete),
+        None => Err(ParseError::Invalid("expected CRLF")),
+    }
+}
 
-fn parse_simple_string(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+/// Splits off everything up to the next CRLF, for the line-based types.
+/// Lines cannot contain a CR, so the first one must start the CRLF.
+fn parse_line(i: &[u8]) -> ParseResult<'_, &[u8]> {
+    let pos = i.iter().position(|&byte| byte == b'\r').ok_or(ParseError::Incomplete)?;
+    Ok((parse_crlf(&i[pos..])?, &i[..pos]))
+}
+
+fn parse_simple_string(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b'+', i);
-    if let Some(pos) = i.windows(2).position(|window| window == CRLF) {
-        let content = &i[..pos];
-        let message = Message::SimpleString(String::from_utf8_lossy(content).to_string());
-        let remaining = &i[pos+2..];
-        Ok((remaining, message))
-    } else {
-        Err("simple string parse error")
-    }
+    let (remaining, content) = parse_line(i)?;
+    let message = Message::SimpleString(String::from_utf8_lossy(content).to_string());
+    Ok((remaining, message))
 }
 
-fn parse_error(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+fn parse_error(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b'-', i);
-    if let Some(pos) = i.windows(2).position(|window| window == CRLF) {
-        let content = &i[..pos];
-        let message = Message::SimpleString(String::from_utf8_lossy(content).to_string());
-        let remaining = &i[pos+2..];
-        Ok((remaining, message))
-    } else {
-        Err("error parse error")
-    }
+    let (remaining, content) = parse_line(i)?;
+    let message = Message::Error(String::from_utf8_lossy(content).to_string());
+    Ok((remaining, message))
 }
 
-fn parse_signed_integer(mut i: &[u8]) -> Result<(&[u8], isize), &str> {
-    let maybe_sign = i.get(0).unwrap();
-    if b"+-".contains(maybe_sign) {
+/// Reads an optionally signed decimal, accumulating digits as they are
+/// scanned.
+fn parse_signed_integer(mut i: &[u8]) -> ParseResult<'_, isize> {
+    let sign = *i.first().ok_or(ParseError::Incomplete)?;
+    if b"+-".contains(&sign) {
         i = &i[1..];
     }
-    if let Some(pos) = i.windows(1).position(|window| !window[0].is_ascii_digit()) {
-        let content = &i[..pos];
-        let mut number: isize = String::from_utf8_lossy(content).parse().unwrap();
-        if *maybe_sign == b'-' { number = -number; }
-        Ok((&i[pos..], number))
+    let negative = sign == b'-';
+    let mut number: isize = 0;
+    let mut digits = 0;
+    for &byte in i.iter().take_while(|byte| byte.is_ascii_digit()) {
+        let digit = (byte - b'0') as isize;
+        // Negative numbers are accumulated downwards so isize::MIN fits
+        number = number
+            .checked_mul(10)
+            .and_then(|number| if negative { number.checked_sub(digit) } else { number.checked_add(digit) })
+            .ok_or(ParseError::Invalid("integer out of range"))?;
+        digits += 1;
+    }
+    if digits == i.len() {
+        Err(ParseError::Incomplete)
+    } else if digits == 0 {
+        Err(ParseError::Invalid("expected integer"))
     } else {
-        Err("parse signed int error")
+        Ok((&i[digits..], number))
     }
 }
 
-fn parse_integer(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+fn parse_integer(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b':', i);
-    let (i, n) = parse_signed_integer(i).unwrap();
+    let (i, n) = parse_signed_integer(i)?;
     let message = Message::Integer(n);
-    Ok((&i[2..], message))
+    Ok((parse_crlf(i)?, message))
 }
 
-fn parse_bulk_string(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
-    check_tag!(b'$', i);
-    let (mut i, length) = parse_signed_integer(i).unwrap();
+/// Parses the `<tag><length>\r\n` header that starts bulk strings and arrays.
+fn parse_header(tag: u8, mut i: &[u8]) -> ParseResult<'_, isize> {
+    check_tag!(tag, i);
+    let (i, length) = parse_signed_integer(i)?;
+    Ok((parse_crlf(i)?, length))
+}
 
+/// Parses a bulk string header, giving `None` for the null bulk string.
+/// Lengths over the configured limit are rejected before any of the payload
+/// is waited for.
+pub(super) fn parse_bulk_header(i: &[u8]) -> ParseResult<'_, Option<usize>> {
+    let (i, length) = parse_header(b'$', i)?;
     if length == -1 {
-        return Ok((&i[2..], Message::BulkString(None)));
+        return Ok((i, None));
+    }
+    let length = usize::try_from(length).map_err(|_| ParseError::Invalid("invalid bulk string length"))?;
+    if length > config::get().max_bulk_len {
+        return Err(ParseError::Invalid("bulk string too long"));
     }
+    Ok((i, Some(length)))
+}
 
-    i = &i[2..]; //CRLF
-    let length = length as usize;
-    let content = &i[..length];
-    let string = String::from_utf8_lossy(content).to_string();
-    let message = Message::BulkString(Some(string));
-    return Ok((&i[length+2..], message));
+/// Parses an array header, giving `None` for the null array. Lengths over
+/// the configured limit are rejected before any element is parsed.
+pub(crate) fn parse_array_header(i: &[u8]) -> ParseResult<'_, Option<usize>> {
+    let (i, length) = parse_header(b'*', i)?;
+    if length == -1 {
+        return Ok((i, None));
+    }
+    let length = usize::try_from(length).map_err(|_| ParseError::Invalid("invalid array length"))?;
+    if length > config::get().max_array_len {
+        return Err(ParseError::Invalid("array too long"));
+    }
+    Ok((i, Some(length)))
 }
 
-fn parse_array(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
-    check_tag!(b'*', i);
-    let (mut i, length) = parse_signed_integer(i).unwrap();
+fn parse_bulk_string(i: &[u8]) -> ParseResult<'_, Message> {
+    let (i, length) = parse_bulk_header(i)?;
+    let Some(length) = length else {
+        return Ok((i, Message::BulkString(None)));
+    };
 
-    if length == -1 {
-        return Ok((&i[2..], Message::Array(None)));
+    if i.len() < length {
+        return Err(ParseError::Incomplete);
     }
+    let message = Message::BulkString(Some(i[..length].to_vec()));
+    Ok((parse_crlf(&i[length..])?, message))
+}
 
-    i = &i[2..]; // CRLF
-    let length = length as usize;
-    let mut messages = Vec::new();
-    for _ in 0..length {
-        let (remaining, message) = parse_message(i).unwrap();
+/// Parses an array and everything nested in it without recursing. Arrays
+/// that are still being filled are kept on a stack, innermost last, with the
+/// number of elements each one is waiting for. Arrays nested deeper than the
+/// configured limit are rejected.
+fn parse_array(mut i: &[u8]) -> ParseResult<'_, Message> {
+    let max_depth = config::get().max_nesting_depth;
+    let mut open: Vec<(Vec<Message>, usize)> = Vec::new();
+    loop {
+        let (remaining, mut message) = if open.is_empty() || i.first() == Some(&b'*') {
+            if open.len() == max_depth {
+                return Err(ParseError::Invalid("too many nested arrays"));
+            }
+            match parse_array_header(i)? {
+                (remaining, None) => (remaining, Message::Array(None)),
+                (remaining, Some(0)) => (remaining, Message::Array(Some(Vec::new()))),
+                (remaining, Some(length)) => {
+                    open.push((Vec::with_capacity(length.min(MAX_PREALLOC)), length));
+                    i = remaining;
+                    continue;
+                }
+            }
+        } else {
+            parse_message(i)?
+        };
         i = remaining;
-        messages.push(message);
+
+        // Add the message to its array, closing every array this completes
+        loop {
+            let Some((elements, length)) = open.last_mut() else {
+                return Ok((i, message));
+            };
+            elements.push(message);
+            if elements.len() < *length {
+                break;
+            }
+            let (elements, _) = open.pop().unwrap();
+            message = Message::Array(Some(elements));
+        }
     }
-    Ok((i, Message::Array(Some(messages))))
 }
 
-fn parse_null(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+fn parse_null(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b'_', i);
-    Ok((&i[2..], Message::Null))
+    Ok((parse_crlf(i)?, Message::Null))
 }
 
-fn parse_bool(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+fn parse_bool(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b'#', i);
-    let value = match i.get(0).unwrap() {
-        b't' => true,
-        b'f' => false,
-        _ => panic!(),
+    let value = match i.first() {
+        Some(b't') => true,
+        Some(b'f') => false,
+        Some(_) => return Err(ParseError::Invalid("expected t or f")),
+        None => return Err(ParseError::Incomplete),
     };
 
-    Ok((&i[3..], Message::Bool(value)))
+    Ok((parse_crlf(&i[1..])?, Message::Bool(value)))
 }
 
-fn parse_double(mut i: &[u8]) -> Result<(&[u8], Message), &str> {
+fn parse_double(mut i: &[u8]) -> ParseResult<'_, Message> {
     check_tag!(b',', i);
-    if let Some(pos) = i.windows(2).position(|window| window == CRLF) {
-        let content = &i[..pos];
-        let double = str::from_utf8(content).unwrap().parse::<f64>().unwrap();
-        Ok((&i[pos+2..], Message::Double(double)))
-    } else {
-        Err("")
-    }
+    let (remaining, content) = parse_line(i)?;
+    let double = str::from_utf8(content)
+        .ok()
+        .and_then(|content| content.parse::<f64>().ok())
+        .ok_or(ParseError::Invalid("invalid double"))?;
+    Ok((remaining, Message::Double(double)))
 }
 
 // Main export
-pub(crate) fn parse_message(i: &[u8]) -> Result<(&[u8], Message), &str> {
-    let tag= i.get(0).unwrap();
-    let (remaining, message) = match *tag {
-        b'+' => parse_simple_string(i),
-        b'-' => parse_error(i),
-        b':' => parse_integer(i),
-        b'$' => parse_bulk_string(i),
-        b'*' => parse_array(i),
-        b'_' => parse_null(i),
-        b'#' => parse_bool(i),
-        b',' => parse_double(i),
-        _ => panic!(),
-    }.unwrap();
-    Ok((remaining, message))
+pub(crate) fn parse_message(i: &[u8]) -> ParseResult<'_, Message> {
+    let tag = *i.first().ok_or(ParseError::Incomplete)?;
+    let parser = PARSERS[tag as usize].ok_or(ParseError::Invalid("unknown message type"))?;
+    parser(i)
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod test {
-    use crate::messages::*;
+    use std::time::Instant;
+
+    use super::*;
 
-    fn parse_double_helper(input: &[u8]) -> IResult<&[u8], Message> {
+    fn parse_double_helper(input: &[u8]) -> ParseResult<'_, Message> {
         parse_double(input)
     }
 
@@ -170,7 +257,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.456));
-                assert_eq!(remaining, &[]); // No remaining input
+                assert_eq!(remaining, &[] as &[u8]); // No remaining input
             }
             Err(e) => panic!("Failed to parse valid double: {:?}", e),
         }
@@ -181,7 +268,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.456));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with positive sign: {:?}", e),
         }
@@ -192,7 +279,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(-123.456));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with negative sign: {:?}", e),
         }
@@ -203,7 +290,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.456e+7));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with exponent: {:?}", e),
         }
@@ -214,7 +301,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.456e-7));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with negative exponent: {:?}", e),
         }
@@ -225,7 +312,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.456));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with fractional part: {:?}", e),
         }
@@ -236,7 +323,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(123.0));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse integer as double: {:?}", e),
         }
@@ -245,7 +332,7 @@ mod test {
         let input = b",abc\r\n";
         let result = parse_double_helper(input);
         match result {
-            Ok((remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
+            Ok((_remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
             Err(e) => {
                 println!("Expected error: {:?}", e);
                 assert!(true); // Test passes because error was expected
@@ -256,7 +343,7 @@ mod test {
         let input = b",123.456";
         let result = parse_double_helper(input);
         match result {
-            Ok((remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
+            Ok((_remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
             Err(e) => {
                 println!("Expected error: {:?}", e);
                 assert!(true);
@@ -267,7 +354,7 @@ mod test {
         let input = b"123.456\r\n"; // Missing leading comma
         let result = parse_double_helper(input);
         match result {
-            Ok((remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
+            Ok((_remaining, parsed)) => panic!("Expected error, but parsed: {:?}", parsed),
             Err(e) => {
                 println!("Expected error: {:?}", e);
                 assert!(true);
@@ -280,7 +367,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(1.23E+4));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with uppercase 'E': {:?}", e),
         }
@@ -291,7 +378,7 @@ mod test {
         match result {
             Ok((remaining, parsed)) => {
                 assert_eq!(parsed, Message::Double(1.23e+4));
-                assert_eq!(remaining, &[]);
+                assert_eq!(remaining, &[] as &[u8]);
             }
             Err(e) => panic!("Failed to parse double with lowercase 'e': {:?}", e),
         }
@@ -303,7 +390,7 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed); // Print the parsed result
                 assert_eq!(
                     parsed,
@@ -396,7 +483,7 @@ mod test {
         let result = parse_bulk_string(input);
         assert_eq!(
             result,
-            Ok((&[][..], Message::BulkString(Some("hello".to_string()))))
+            Ok((&[][..], Message::BulkString(Some(b"hello".to_vec()))))
         );
 
         // Test valid bulk string with zero length
@@ -404,7 +491,7 @@ mod test {
         let result = parse_bulk_string(input);
         assert_eq!(
             result,
-            Ok((&[][..], Message::BulkString(Some("".to_string()))))
+            Ok((&[][..], Message::BulkString(Some(Vec::new()))))
         );
 
         // Test invalid bulk string (non-digit length)
@@ -424,12 +511,12 @@ mod test {
     }
 
     // Helper function to test parsing of arrays
-    fn parse_array_helper(input: &[u8]) -> IResult<&[u8], Message> {
+    fn parse_array_helper(input: &[u8]) -> ParseResult<'_, Message> {
         parse_array(input)
     }
 
     // Helper function to print errors in a human-readable ASCII format
-    fn print_error(input: &[u8], error: nom::Err<nom::error::Error<&[u8]>>) {
+    fn print_error(input: &[u8], error: ParseError) {
         // Convert the input bytes to a human-readable string (ASCII)
         let readable_input = String::from_utf8_lossy(input);
         println!(
@@ -444,14 +531,14 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed); // Print the parsed result
                 assert_eq!(
                     parsed,
                     Message::Array(Some(vec![
                         Message::SimpleString("hello".to_string()),
                         Message::Integer(123),
-                        Message::BulkString(Some("world".to_string())),
+                        Message::BulkString(Some(b"world".to_vec())),
                     ]))
                 );
             }
@@ -468,7 +555,7 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed);
                 assert_eq!(parsed, Message::Array(Some(vec![])));
             }
@@ -485,7 +572,7 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed);
                 assert_eq!(
                     parsed,
@@ -505,7 +592,7 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed);
                 panic!("Expected error, but parsed: {:?}", parsed);
             }
@@ -522,7 +609,7 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed);
                 panic!("Expected error, but parsed: {:?}", parsed);
             }
@@ -539,13 +626,13 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed); // Print parsed result
                 assert_eq!(
                     parsed,
                     Message::Array(Some(vec![
                         Message::SimpleString("simple".to_string()),
-                        Message::BulkString(Some("bulk1".to_string())),
+                        Message::BulkString(Some(b"bulk1".to_vec())),
                         Message::Integer(456),
                         Message::Error("Error".to_string())
                     ]))
@@ -565,16 +652,16 @@ mod test {
         let result = parse_array_helper(input);
 
         match result {
-            Ok((remaining, parsed)) => {
+            Ok((_remaining, parsed)) => {
                 println!("Parsed: {:?}", parsed); // Print the parsed result
 
                 // Assert that the result is the expected array with the three elements
                 assert_eq!(
                     parsed,
                     Message::Array(Some(vec![
-                        Message::BulkString(Some("hello".to_string())),
+                        Message::BulkString(Some(b"hello".to_vec())),
                         Message::BulkString(None), // NULL element
-                        Message::BulkString(Some("world".to_string())),
+                        Message::BulkString(Some(b"world".to_vec())),
                     ]))
                 );
             }
@@ -584,4 +671,114 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_incomplete_and_invalid() {
+        // Truncated input could still become a valid message
+        for input in [&b""[..], b"+OK", b"+OK\r", b":12", b"$5\r\nhel", b"$5\r\nhello\r", b"*2\r\n$1\r\na\r\n", b"#t"] {
+            assert_eq!(parse_message(input), Err(ParseError::Incomplete), "{:?}", input);
+        }
+        // Input that can never become valid
+        for input in [&b"!"[..], b":1x\r\n", b"$x\r\n", b"$1\r\nab\r\n", b"#x\r\n", b"*1\r\n?\r\n"] {
+            assert!(matches!(parse_message(input), Err(ParseError::Invalid(_))), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_integer_limits() {
+        assert_eq!(parse_integer(b":9223372036854775807\r\n"), Ok((&[][..], Message::Integer(isize::MAX))));
+        assert_eq!(parse_integer(b":-9223372036854775808\r\n"), Ok((&[][..], Message::Integer(isize::MIN))));
+        assert!(matches!(parse_integer(b":9223372036854775808\r\n"), Err(ParseError::Invalid(_))));
+        assert!(matches!(parse_integer(b":+\r\n"), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_nested_arrays() {
+        let input = b"*3\r\n*2\r\n:1\r\n*0\r\n*-1\r\n*1\r\n*1\r\n+deep\r\n:2\r\n";
+        let nested = Message::Array(Some(vec![
+            Message::Array(Some(vec![Message::Integer(1), Message::Array(Some(vec![]))])),
+            Message::Array(None),
+            Message::Array(Some(vec![Message::Array(Some(vec![Message::SimpleString("deep".to_string())]))])),
+        ]));
+        assert_eq!(parse_message(input), Ok((&b":2\r\n"[..], nested)));
+        // Every prefix is incomplete rather than invalid
+        for end in 0..input.len() - 4 {
+            assert_eq!(parse_message(&input[..end]), Err(ParseError::Incomplete), "{:?}", &input[..end]);
+        }
+    }
+
+    #[test]
+    fn test_nesting_depth_limit() {
+        let depth = config::get().max_nesting_depth;
+        let nested = |depth: usize| format!("{}:1\r\n", "*1\r\n".repeat(depth));
+
+        let deepest = nested(depth);
+        let (remaining, mut message) = parse_message(deepest.as_bytes()).unwrap();
+        assert!(remaining.is_empty());
+        for _ in 0..depth {
+            let Message::Array(Some(mut elements)) = message else { panic!("expected an array") };
+            message = elements.pop().unwrap();
+        }
+        assert_eq!(message, Message::Integer(1));
+
+        // Rejected as soon as the array that is too deep starts, even before
+        // the rest of the frame arrives
+        let too_deep = nested(depth + 1);
+        assert_eq!(parse_message(too_deep.as_bytes()), Err(ParseError::Invalid("too many nested arrays")));
+        assert_eq!(
+            parse_message(&too_deep.as_bytes()[..(depth + 1) * 4]),
+            Err(ParseError::Invalid("too many nested arrays"))
+        );
+        // Far deeper frames are rejected without exhausting the stack
+        assert!(parse_message(nested(1_000_000).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_length_limits() {
+        let config = config::get();
+        let header = |tag: char, length: usize| format!("{}{}\r\n", tag, length);
+
+        // At the limit the parser waits for the rest of the frame
+        assert_eq!(parse_message(header('*', config.max_array_len).as_bytes()), Err(ParseError::Incomplete));
+        assert_eq!(parse_message(header('$', config.max_bulk_len).as_bytes()), Err(ParseError::Incomplete));
+        // Over it the header alone is enough to reject the frame
+        assert_eq!(
+            parse_message(header('*', config.max_array_len + 1).as_bytes()),
+            Err(ParseError::Invalid("array too long"))
+        );
+        assert_eq!(
+            parse_message(header('$', config.max_bulk_len + 1).as_bytes()),
+            Err(ParseError::Invalid("bulk string too long"))
+        );
+        assert_eq!(
+            parse_message(format!("*1\r\n{}", header('$', usize::MAX >> 1)).as_bytes()),
+            Err(ParseError::Invalid("bulk string too long"))
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn bench_parse_pipeline() {
+        const ROUNDS: u32 = 20;
+        const COMMANDS: usize = 100_000;
+        let pipeline = "*3\r\n$3\r\nSET\r\n$10\r\nkey:000042\r\n$16\r\nvalue-0123456789\r\n".repeat(COMMANDS);
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut i = std::hint::black_box(pipeline.as_bytes());
+            while !i.is_empty() {
+                let (remaining, message) = parse_message(i).unwrap();
+                std::hint::black_box(message);
+                i = remaining;
+            }
+        }
+        let elapsed = start.elapsed() / ROUNDS;
+        println!(
+            "Parsed {} pipelined SETs ({} bytes) in {:?}: {:.0} commands/s",
+            COMMANDS,
+            pipeline.len(),
+            elapsed,
+            COMMANDS as f64 / elapsed.as_secs_f64(),
+        );
+    }
 }
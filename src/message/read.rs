@@ -0,0 +1,238 @@
+use std::io::{self, Read};
+use std::mem;
+
+use super::parse::{parse_array_header, parse_bulk_header, ParseError, ParseResult};
+use super::Message;
+use crate::config::Config;
+
+const CRLF: &[u8] = b"\r\n";
+const READ_SIZE: usize = 1024;
+
+/// Bulk arguments at least this long are read from the socket straight into
+/// their own allocation instead of going through the connection buffer.
+const LARGE_BULK: usize = 32 * 1024;
+
+/// Reads a multibulk request (an array of bulk strings, the form clients
+/// send commands in) whose start is already in `buffer`, reading the rest
+/// from `reader`.
+///
+/// Arguments shorter than `LARGE_BULK` are read through `buffer`. For longer
+/// ones an exactly sized allocation is made as soon as the length is known
+/// and the payload is read directly into it, so a multi-megabyte value is
+/// never held in the buffer and the message at the same time. The request is
+/// removed from `buffer`; any bytes after it are left there.
+///
+/// Requests with more than `max_multibulk_len` arguments are refused from
+/// their first header. Each argument is counted against the connection's
+/// `client_query_buffer_limit` as its header arrives, before anything is
+/// allocated for it.
+///
+/// Malformed requests fail with `io::ErrorKind::InvalidData`.
+pub(crate) fn read_request(buffer: &mut Vec<u8>, reader: &mut impl Read, limits: &Config) -> io::Result<Message> {
+    let mut pos = 0;
+    let count = read_header(parse_array_header, buffer, &mut pos, reader)?;
+    let count = count
+        .filter(|count| *count <= limits.max_multibulk_len)
+        .ok_or_else(|| invalid("invalid multibulk length"))?;
+
+    // What the connection will be holding once the request is read
+    let mut reserved = buffer.len().saturating_add(count.saturating_mul(mem::size_of::<Message>()));
+
+    let mut arguments = Vec::with_capacity(count.min(READ_SIZE));
+    for _ in 0..count {
+        let length = read_header(parse_bulk_header, buffer, &mut pos, reader)?;
+        let length = length.ok_or_else(|| invalid("invalid bulk length"))?;
+        reserved = reserved.saturating_add(length + 2);
+        if reserved > limits.client_query_buffer_limit {
+            return Err(invalid("query buffer limit exceeded"));
+        }
+
+        let payload = if length >= LARGE_BULK {
+            let mut payload = Vec::with_capacity(length + 2);
+            let buffered = (buffer.len() - pos).min(length + 2);
+            payload.extend_from_slice(&buffer[pos..pos + buffered]);
+            pos += buffered;
+            payload.resize(length + 2, 0);
+            reader.read_exact(&mut payload[buffered..])?;
+            if !payload.ends_with(CRLF) {
+                return Err(invalid("expected CRLF"));
+            }
+            payload.truncate(length);
+            payload
+        } else {
+            while buffer.len() - pos < length + 2 {
+                fill(buffer, reader)?;
+            }
+            if &buffer[pos + length..pos + length + 2] != CRLF {
+                return Err(invalid("expected CRLF"));
+            }
+            let payload = buffer[pos..pos + length].to_vec();
+            pos += length + 2;
+            payload
+        };
+        arguments.push(Message::BulkString(Some(payload)));
+    }
+
+    buffer.drain(..pos);
+    Ok(Message::Array(Some(arguments)))
+}
+
+fn read_header(
+    parse: fn(&[u8]) -> ParseResult<'_, Option<usize>>,
+    buffer: &mut Vec<u8>,
+    pos: &mut usize,
+    reader: &mut impl Read,
+) -> io::Result<Option<usize>> {
+    loop {
+        match parse(&buffer[*pos..]) {
+            Ok((remaining, length)) => {
+                *pos = buffer.len() - remaining.len();
+                return Ok(length);
+            }
+            Err(ParseError::Incomplete) => fill(buffer, reader)?,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Appends the next read from `reader` to `buffer`.
+fn fill(buffer: &mut Vec<u8>, reader: &mut impl Read) -> io::Result<()> {
+    let mut chunk = [0; READ_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ParseError::Invalid(reason))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::command::{handle_command, parse_command, Client};
+    use crate::config;
+    use crate::storage::{MemoryStorage, DB};
+
+    fn bulk(string: &str) -> Message {
+        Message::BulkString(Some(string.into()))
+    }
+
+    #[test]
+    fn test_split_request() {
+        let mut buffer = b"*2\r\n$3\r\nGET".to_vec();
+        let mut reader = Cursor::new(b"\r\n$3\r\nkey\r\n*1\r\n$4\r\nPING\r\n".to_vec());
+        let message = read_request(&mut buffer, &mut reader, config::get()).unwrap();
+        assert_eq!(message, Message::Array(Some(vec![bulk("GET"), bulk("key")])));
+        // The next request stays buffered
+        assert_eq!(buffer, b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn test_large_argument_bypasses_buffer() {
+        let value = "v".repeat(4 * 1024 * 1024);
+        let request = format!("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n{}\r\n*1\r\n$4\r\nPING\r\n", value.len(), value);
+        // The connection has buffered the start of the request
+        let (start, rest) = request.as_bytes().split_at(READ_SIZE);
+        let mut buffer = start.to_vec();
+        let mut reader = Cursor::new(rest.to_vec());
+
+        let message = read_request(&mut buffer, &mut reader, config::get()).unwrap();
+        let arguments = message.as_array().unwrap();
+        assert_eq!(arguments[..2], [bulk("SET"), bulk("key")]);
+        let Message::BulkString(Some(payload)) = &arguments[2] else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(payload, value.as_bytes());
+        assert_eq!(payload.capacity(), value.len() + 2);
+
+        // The payload never went through the buffer, and reading stopped at
+        // the end of the request
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() < LARGE_BULK);
+        assert_eq!(&rest[reader.position() as usize..], b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn test_large_binary_argument() {
+        let value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i * 7) as u8).collect();
+        assert!(std::str::from_utf8(&value).is_err());
+        let mut request = format!("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n", value.len()).into_bytes();
+        request.extend_from_slice(&value);
+        request.extend_from_slice(b"\r\n");
+        let (start, rest) = request.split_at(READ_SIZE);
+        let mut buffer = start.to_vec();
+
+        let mut message = read_request(&mut buffer, &mut Cursor::new(rest), config::get()).unwrap();
+        // Read into one allocation, and kept there
+        let Message::BulkString(Some(payload)) = &message.as_array().unwrap()[2] else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(payload.capacity(), value.len() + 2);
+
+        let db: DB = Arc::new(MemoryStorage::new());
+        let mut command = parse_command(&mut message).unwrap();
+        handle_command(&mut command, &db, &mut Client::default(), Duration::ZERO);
+        assert!(*db.get(b"key").unwrap().unwrap() == value);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let mut buffer = b"*1\r\n:1\r\n".to_vec();
+        let error = read_request(&mut buffer, &mut io::empty(), config::get()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut buffer = b"*1\r\n$1\r\nab\r\n".to_vec();
+        let error = read_request(&mut buffer, &mut io::empty(), config::get()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        // Rejected from the header, before anything is allocated or read
+        let mut buffer = format!("*1\r\n${}\r\n", config::get().max_bulk_len + 1).into_bytes();
+        let error = read_request(&mut buffer, &mut io::empty(), config::get()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Protocol error: bulk string too long");
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let limits = Config { max_multibulk_len: 2, client_query_buffer_limit: 256, ..Config::default() };
+
+        let mut buffer = b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n".to_vec();
+        assert!(read_request(&mut buffer, &mut io::empty(), &limits).is_ok());
+
+        // Too many arguments, refused before any of them arrive
+        let mut buffer = b"*3\r\n".to_vec();
+        let error = read_request(&mut buffer, &mut io::empty(), &limits).unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: invalid multibulk length");
+
+        // The second argument would take the request over the limit, so it
+        // is refused without being read
+        let mut buffer = b"*2\r\n$100\r\n".to_vec();
+        buffer.extend_from_slice(&[b'a'; 100]);
+        buffer.extend_from_slice(b"\r\n$200\r\n");
+        let error = read_request(&mut buffer, &mut io::empty(), &limits).unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: query buffer limit exceeded");
+    }
+
+    #[test]
+    fn test_disconnect_mid_request() {
+        let mut buffer = b"*2\r\n$3\r\nGET\r\n".to_vec();
+        let error = read_request(&mut buffer, &mut io::empty(), config::get()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
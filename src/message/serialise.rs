@@ -25,7 +25,7 @@ fn serialise_simple_string(string: &str) -> Vec<u8> {
 
 fn serialise_error(error: &str) -> Vec<u8> {
     let mut buf = Vec::with_capacity(error.len() + 3);
-    buf.push(b'+');
+    buf.push(b'-');
     buf.extend_from_slice(error.as_bytes());
     buf.extend_from_slice(CRLF);
     buf
@@ -40,7 +40,7 @@ fn serialise_integer(n: isize) -> Vec<u8> {
     buf
 }
 
-fn serialise_bulk_string(string: &Option<String>) -> Vec<u8> {
+fn serialise_bulk_string(string: &Option<Vec<u8>>) -> Vec<u8> {
     if let Some(ref string) = string {
         let len = string.len();
         let len_str = len.to_string();
@@ -48,7 +48,7 @@ fn serialise_bulk_string(string: &Option<String>) -> Vec<u8> {
         buf.push(b'$');
         buf.extend_from_slice(len_str.as_bytes());
         buf.extend_from_slice(CRLF);
-        buf.extend_from_slice(string.as_bytes());
+        buf.extend_from_slice(string);
         buf.extend_from_slice(CRLF);
         buf
     } else {
@@ -91,4 +91,46 @@ fn serialise_array(array: &Option<Vec<Message>>) -> Vec<u8> {
     } else {
         "*-1\r\n".into()
     }
-}
\ No newline at end of file
+}
+
+/// How many bytes `serialise_message` would produce, without building them.
+pub(crate) fn serialised_len(message: &Message) -> usize {
+    match message {
+        Message::SimpleString(string) | Message::Error(string) => string.len() + 3,
+        Message::Integer(n) => decimal_len(n.unsigned_abs()) + usize::from(*n < 0) + 3,
+        Message::BulkString(Some(string)) => decimal_len(string.len()) + string.len() + 5,
+        Message::BulkString(None) | Message::Array(None) => 5,
+        Message::Array(Some(array)) => decimal_len(array.len()) + 3 + array.iter().map(serialised_len).sum::<usize>(),
+        Message::Null => 3,
+        Message::Bool(_) => 4,
+        Message::Double(n) => n.to_string().len() + 3,
+    }
+}
+
+fn decimal_len(n: usize) -> usize {
+    n.checked_ilog10().map_or(1, |digits| digits as usize + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialised_len() {
+        let messages = [
+            Message::SimpleString("OK".to_string()),
+            Message::Error("ERR x".to_string()),
+            Message::Integer(0),
+            Message::Integer(-100),
+            Message::Integer(isize::MIN),
+            Message::BulkString(Some(vec![0xff; 10])),
+            Message::BulkString(Some(Vec::new())),
+            Message::BulkString(None),
+            Message::Array(None),
+            Message::Array(Some(vec![Message::Null, Message::Bool(true), Message::Double(-1.5)])),
+        ];
+        for message in messages {
+            assert_eq!(serialised_len(&message), serialise_message(&message).len(), "{:?}", message);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::command::{parse_command, Client, ClientCommand, Command};
+use crate::message::{parse_message, serialise_message, Message, ParseError};
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// Writes every inbound frame to a file, one JSON object per line:
+/// `{"conn": 3, "frame": ["SET", "key", "value"], "t_us": 1234}`, where
+/// `t_us` is microseconds since recording started and `conn` the client id.
+struct Recorder {
+    started: Instant,
+    // Flushed line by line, so a crash loses at most the frame being written
+    file: Mutex<LineWriter<File>>,
+}
+
+/// Starts recording inbound frames to `path`, replacing whatever is there.
+pub(crate) fn start_recording(path: &Path) -> io::Result<()> {
+    let recorder = Recorder {
+        started: Instant::now(),
+        file: Mutex::new(LineWriter::new(File::create(path)?)),
+    };
+    let _ = RECORDER.set(recorder);
+    Ok(())
+}
+
+/// Records a frame received from client `conn`, if recording.
+pub(crate) fn record(conn: u64, frame: &Message) {
+    if let Some(recorder) = RECORDER.get() {
+        recorder.record(conn, frame);
+    }
+}
+
+impl Recorder {
+    fn record(&self, conn: u64, frame: &Message) {
+        let t_us = self.started.elapsed().as_micros() as u64;
+        let line = json!({ "t_us": t_us, "conn": conn, "frame": frame.to_json() });
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            eprintln!("Failed to record frame: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ReplayError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("line {0}: {1}")]
+    InvalidLine(usize, String),
+
+    #[error("bad reply from the server: {0}")]
+    Reply(ParseError),
+}
+
+/// One line of a recording
+struct Recorded {
+    t_us: u64,
+    conn: u64,
+    frame: Message,
+}
+
+fn parse_recorded(line: &str) -> Result<Recorded, String> {
+    let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let field = |name| value.get(name).and_then(Value::as_u64).ok_or(format!("missing {}", name));
+    let (t_us, conn) = (field("t_us")?, field("conn")?);
+    let frame = value.get("frame").ok_or("missing frame")?;
+    let frame = Message::from_json(frame).map_err(|e| e.to_string())?;
+    Ok(Recorded { t_us, conn, frame })
+}
+
+/// A connection standing in for one recorded client
+struct Replayer {
+    stream: TcpStream,
+    /// Mirrors the server's view of the client, to know which frames get a
+    /// reply
+    client: Client,
+    buffer: Vec<u8>,
+}
+
+impl Replayer {
+    /// Sends a frame and, if the server will answer it, waits for the
+    /// answer, so the server has run it before the next frame goes out.
+    fn send(&mut self, mut frame: Message) -> Result<(), ReplayError> {
+        self.stream.write_all(&serialise_message(&frame))?;
+        if let Ok(Command::CLIENT(ClientCommand::Reply(mode))) = parse_command(&mut frame) {
+            self.client.set_reply_mode(mode);
+        }
+        if !self.client.should_reply() {
+            return Ok(());
+        }
+        let mut chunk = [0; 4096];
+        loop {
+            match parse_message(&self.buffer) {
+                Ok((remaining, _)) => {
+                    let used = self.buffer.len() - remaining.len();
+                    self.buffer.drain(..used);
+                    return Ok(());
+                }
+                Err(ParseError::Incomplete) => {}
+                Err(e) => return Err(ReplayError::Reply(e)),
+            }
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                n => self.buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+/// Feeds a recording to the server at `address`, one connection per
+/// recorded client.
+///
+/// Frames go out in recorded order, each after the reply to the one before,
+/// so the server runs them in the same order as when they were recorded.
+/// The exception is clients that turned replies off with CLIENT REPLY:
+/// there is nothing to wait for, so their frames may run a little later.
+/// `speed` scales the gaps between them: 1 keeps the original timing, 2
+/// halves it, and 0 sends each frame as soon as the last one is answered.
+/// Returns how many frames were sent.
+pub(crate) fn replay(path: &Path, address: &str, speed: f64) -> Result<usize, ReplayError> {
+    let started = Instant::now();
+    let mut replayers: HashMap<u64, Replayer> = HashMap::new();
+    let mut frames = 0;
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let recorded = parse_recorded(&line).map_err(|e| ReplayError::InvalidLine(i + 1, e))?;
+        if speed > 0.0 {
+            let due = started + Duration::from_micros(recorded.t_us).div_f64(speed);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        let replayer = match replayers.entry(recorded.conn) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Replayer {
+                stream: TcpStream::connect(address)?,
+                client: Client::default(),
+                buffer: Vec::new(),
+            }),
+        };
+        replayer.send(recorded.frame)?;
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::{handle_client, Transport};
+    use crate::storage::{MemoryStorage, DB};
+
+    fn frame(args: &[&str]) -> Message {
+        Message::Array(Some(args.iter().map(|arg| Message::BulkString(Some(arg.as_bytes().to_vec()))).collect()))
+    }
+
+    #[test]
+    fn test_record_format() {
+        let path = std::env::temp_dir().join(format!("redirs-test-{}.record", std::process::id()));
+        let recorder = Recorder { started: Instant::now(), file: Mutex::new(LineWriter::new(File::create(&path).unwrap())) };
+        recorder.record(7, &frame(&["SET", "k", "v"]));
+        drop(recorder);
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let recorded = parse_recorded(line.trim_end()).unwrap();
+        assert_eq!((recorded.conn, recorded.frame), (7, frame(&["SET", "k", "v"])));
+        assert!(line.starts_with(r#"{"conn":7,"frame":["SET","k","v"],"t_us":"#), "{}", line);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(parse_recorded(r#"{"conn":1,"frame":["PING"]}"#).is_err());
+        assert!(parse_recorded(r#"{"conn":1,"frame":[{"map":{}}],"t_us":0}"#).is_err());
+    }
+
+    #[test]
+    fn test_replay_keeps_order_across_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let db: DB = Arc::new(MemoryStorage::new());
+        {
+            let db = db.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let db = db.clone();
+                    thread::spawn(move || handle_client(stream.unwrap(), Transport::Tcp, db));
+                }
+            });
+        }
+
+        let path = std::env::temp_dir().join(format!("redirs-test-{}.replay", std::process::id()));
+        let recording = [
+            r#"{"t_us":0,"conn":1,"frame":["APPEND","log","a"]}"#,
+            r#"{"t_us":10,"conn":2,"frame":["CLIENT","REPLY","OFF"]}"#,
+            r#"{"t_us":20,"conn":2,"frame":["APPEND","log","b"]}"#,
+            r#"{"t_us":30,"conn":1,"frame":["APPEND","log","c"]}"#,
+            r#"{"t_us":40,"conn":3,"frame":["NOSUCHCOMMAND"]}"#,
+            r#"{"t_us":50,"conn":1,"frame":["GET","log"]}"#,
+        ];
+        std::fs::write(&path, recording.join("\n")).unwrap();
+        assert_eq!(replay(&path, &address, 0.0).unwrap(), 6);
+        std::fs::remove_file(&path).unwrap();
+
+        // Connection 2 turned replies off, so its APPEND can't be waited for
+        // and may land after connection 1's second one
+        let log = db.get(b"log").unwrap().unwrap();
+        assert!(*log == b"abc" || *log == b"acb", "{:?}", log);
+    }
+}
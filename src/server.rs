@@ -1,75 +1,711 @@
-use std::io::{self, Write, Read};
-use std::net::{TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IoSlice, Write, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use dashmap::DashMap;
-use nom::AsBytes;
+use tracing::{field, info_span, Level, Span};
 
-use crate::command::{handle_command, parse_command};
-use crate::message::{Message, parse_message, serialise_message};
+use crate::allocator::{scope, Subsystem};
+use crate::command::{command_name, handle_command, over_budget, parse_command, Client};
+use crate::config::{self, Config};
+use crate::message::{Message, ParseError, parse_array_header, parse_message, read_request, serialise_message, serialised_len};
+use crate::replay;
+use crate::shutdown::SHUTDOWN;
+use crate::stats::STATS;
+use crate::storage::DB;
+use crate::util::spawn_thread;
 
 const BUFFER_SIZE: usize = 1024;
 
-type DB = DashMap<Vec<u8>, Vec<u8>>;
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
-pub fn listen<F>(
-    ip: &str, 
-    port: &str, 
-    handle_client: F, 
-    db: DB
-) -> io::Result<()> 
+static CONNECTIONS: LazyLock<Connections> = LazyLock::new(Connections::default);
+
+/// A client's socket, which the server may need to hang up on from another
+/// thread while the client's own thread is blocked reading it. The socket
+/// is shared between the two rather than cloned, so each connection holds
+/// one file descriptor. The client's thread reads and writes through `&S`.
+pub(crate) trait ClientStream: Send + Sync + 'static {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl ClientStream for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl ClientStream for UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+/// A connected client, as seen by the thread shutting the server down
+struct Connection {
+    /// Set while the client waits for a new request with nothing buffered
+    idle: AtomicBool,
+    stream: Arc<dyn ClientStream>,
+}
+
+/// Every connected client, so a shutdown can reach ones that are blocked
+/// waiting for their next request.
+#[derive(Default)]
+struct Connections(Mutex<HashMap<u64, Arc<Connection>>>);
+
+impl Connections {
+    fn register(&self, id: u64, stream: Arc<dyn ClientStream>) -> Arc<Connection> {
+        let connection = Arc::new(Connection { idle: AtomicBool::new(false), stream });
+        self.0.lock().unwrap().insert(id, Arc::clone(&connection));
+        connection
+    }
+
+    fn remove(&self, id: u64) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    /// Shuts down the idle connections, or every connection if `idle_only`
+    /// is false. Their threads see the stream end and exit.
+    fn hang_up(&self, idle_only: bool, how: Shutdown) {
+        for connection in self.0.lock().unwrap().values() {
+            if !idle_only || connection.idle.load(Ordering::Relaxed) {
+                let _ = connection.stream.shutdown(how);
+            }
+        }
+    }
+}
+
+/// The kind of endpoint a connection arrived on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Transport {
+    Tcp,
+    Unix,
+}
+
+impl Transport {
+    pub const ALL: [Transport; 2] = [Transport::Tcp, Transport::Unix];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Unix => "unix",
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn transport(&self) -> Transport {
+        match self {
+            Listener::Tcp(_) => Transport::Tcp,
+            Listener::Unix(_) => Transport::Unix,
+        }
+    }
+
+    /// Accepts connections until the listener fails, serving each one on its
+    /// own thread.
+    fn serve(self, db: DB) {
+        match self {
+            Listener::Tcp(listener) => accept_loop(listener.incoming(), Transport::Tcp, db),
+            Listener::Unix(listener) => accept_loop(listener.incoming(), Transport::Unix, db),
+        }
+    }
+
+    /// Where to connect to wake the accept loop up.
+    fn address(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut address = listener.local_addr()?;
+                if address.ip().is_unspecified() {
+                    address.set_ip(match address {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Ok(Address::Tcp(address))
+            }
+            Listener::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().ok_or(io::ErrorKind::AddrNotAvailable)?;
+                Ok(Address::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Connects and hangs up, so an accept loop blocked on this address
+    /// gets to check whether it should stop.
+    fn wake(&self) {
+        let _ = match self {
+            Address::Tcp(address) => TcpStream::connect(address).map(drop),
+            Address::Unix(path) => UnixStream::connect(path).map(drop),
+        };
+    }
+}
+
+/// How often a draining server checks whether its clients have gone
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Binds every endpoint in the config and accepts on all of them at once
+/// until SHUTDOWN. Then it stops accepting and returns once every client
+/// has disconnected. Clients still connected at the shutdown deadline are
+/// cut off, and their threads waited for, so nothing is written after this
+/// returns.
+pub fn listen(config: &Config, db: DB) -> io::Result<()> {
+    let listeners = bind(config)?;
+    if listeners.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "nothing to listen on: set --port or --unixsocket"));
+    }
+    let addresses = listeners.iter().map(Listener::address).collect::<io::Result<Vec<_>>>()?;
+    let threads: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let db = db.clone();
+            let name = format!("accept-{}", listener.transport().name());
+            spawn_thread(&name, &config.server_cpulist, move || listener.serve(db))
+        })
+        .collect();
+
+    let deadline = SHUTDOWN.wait();
+    // Each accept loop drops its listener once woken, so new connections
+    // are refused from here on
+    for address in &addresses {
+        address.wake();
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    if let Some(path) = &config.unix_socket {
+        let _ = fs::remove_file(path);
+    }
+
+    // Idle clients are blocked reading, and wouldn't see the drain until
+    // their next request arrived. Hang up on them; clients partway through
+    // a request get until the deadline to finish it. Clients go idle as
+    // they finish, so this is repeated until they have all gone.
+    while STATS.connected_clients() > 0 && Instant::now() < deadline {
+        CONNECTIONS.hang_up(true, Shutdown::Read);
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    let remaining = STATS.connected_clients();
+    if remaining > 0 {
+        eprintln!("Disconnecting {} clients still connected", remaining);
+        // A command already running may still write, but its reply can't
+        // be sent, so nothing is acknowledged after this
+        CONNECTIONS.hang_up(false, Shutdown::Both);
+        while STATS.connected_clients() > 0 {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Binds the configured endpoints, all or nothing, so a bad address fails
+/// at startup.
+fn bind(config: &Config) -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    if config.port != "0" {
+        listeners.push(Listener::Tcp(TcpListener::bind(format!("{}:{}", config.ip, config.port))?));
+    }
+    if let Some(path) = &config.unix_socket {
+        // A socket left behind by an earlier run would make bind fail. Only
+        // ever remove sockets, never a file that happens to have the name.
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        listeners.push(Listener::Unix(UnixListener::bind(path)?));
+    }
+    Ok(listeners)
+}
+
+fn accept_loop<S>(incoming: impl Iterator<Item = io::Result<S>>, transport: Transport, db: DB)
 where
-    F: Fn(TcpStream, DB) + Send + Copy + 'static,
+    S: ClientStream,
+    for<'a> &'a S: Read + Write,
 {
-    let listener = TcpListener::bind(format!("{}:{}", &ip, &port))?;
-
-    for stream in listener.incoming() {
+    for stream in incoming {
+        // Once shutting down, whatever connected is not served. It may just
+        // be the shutdown waking this loop.
+        if SHUTDOWN.draining() {
+            break;
+        }
         match stream {
             Ok(stream) => {
                 let db_clone = db.clone();
-                thread::spawn(move || { // basic mutlithreaded solution. Maybe do a threadpool
-                    handle_client(stream, db_clone);
+                // Inherits the accept thread's CPU pinning
+                let name = format!("client-{}", transport.name());
+                spawn_thread(&name, &[], move || { // basic mutlithreaded solution. Maybe do a threadpool
+                    handle_client(stream, transport, db_clone);
                 });
             },
             Err(e) => {
-                eprintln!("Failed to accept client {}", e);
+                eprintln!("Failed to accept {} client {}", transport.name(), e);
             }
         }
     }
-
-    Ok(())
 }
 
-pub fn handle_client(mut stream: TcpStream, mut db: DB) 
+pub fn handle_client<S>(stream: S, transport: Transport, db: DB)
 where
+    S: ClientStream,
+    for<'a> &'a S: Read + Write,
 {
-    let mut buffer = [0; BUFFER_SIZE];
+    let _scope = scope(Subsystem::Protocol);
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    let shared = Arc::new(stream);
+    let connection = CONNECTIONS.register(id, Arc::clone(&shared) as Arc<dyn ClientStream>);
+    let mut stream = &*shared;
+    STATS.record_connect(transport);
+    // Bytes read but not yet handled, e.g. the start of a split request
+    let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+    let mut chunk = [0; BUFFER_SIZE];
+    let mut client = Client::new(id);
     loop {
         // println!("{:?}", String::from_utf8_lossy(buffer.as_slice()));
-        match stream.read(&mut buffer) {
+        // Marked idle before checking for a drain, so a shutdown starting
+        // in between sees it and hangs up
+        connection.idle.store(buffer.is_empty(), Ordering::Relaxed);
+        // While draining, let clients go between requests
+        if SHUTDOWN.draining() && buffer.is_empty() {
+            break;
+        }
+        match stream.read(&mut chunk) {
             Ok(0) => {
                 // client disconnected
                 break;
             },
-            Ok(_) => {
-                let message = parse_message(&buffer[..]);
+            Ok(n) => {
+                connection.idle.store(false, Ordering::Relaxed);
+                buffer.extend_from_slice(&chunk[..n]);
+                // A pipelining client can send many commands per read. Answer
+                // every complete one with a single write.
+                let (responses, keep_open) = handle_requests(&mut buffer, &mut stream, &db, &mut client);
+                if write_responses(&mut stream, &responses).is_err() || !keep_open {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            // e.g. the client reset the connection
+            Err(_) => break,
+        }
+    }
+    CONNECTIONS.remove(id);
+    STATS.record_disconnect(transport);
+}
+
+/// Handles every request in `buffer`, reading the rest of one that was split
+/// across reads straight from the stream. Returns the serialised responses and
+/// whether the connection should stay open.
+fn handle_requests(
+    buffer: &mut Vec<u8>,
+    stream: &mut impl Read,
+    db: &DB,
+    client: &mut Client,
+) -> (Vec<Vec<u8>>, bool) {
+    let limits = config::get();
+    let mut responses = Vec::new();
+    let mut consumed = 0;
+    loop {
+        // Refused from the header, before any argument is parsed
+        if too_many_arguments(&buffer[consumed..], limits) {
+            responses.push(protocol_error(ParseError::Invalid("invalid multibulk length")));
+            return (responses, false);
+        }
+        match parse_message(&buffer[consumed..]) {
+            Ok((remaining, message)) => {
                 // println!("{:?}", message);
-                if let Ok((_, message)) = message {
-                    handle_message(&message, &mut stream, &mut db);
-                    buffer.fill(0);
+                responses.extend(handle_message(message, db, client));
+                consumed = buffer.len() - remaining.len();
+                if client.closing() {
+                    return (responses, false);
+                }
+            }
+            Err(ParseError::Incomplete) => {
+                buffer.drain(..consumed);
+                consumed = 0;
+                // Only multibulk requests can be streamed in; anything else
+                // waits in the buffer for the next read
+                if buffer.first() != Some(&b'*') {
+                    if buffer.len() > limits.client_query_buffer_limit {
+                        responses.push(protocol_error(ParseError::Invalid("query buffer limit exceeded")));
+                        return (responses, false);
+                    }
+                    return (responses, true);
                 }
+                match read_request(buffer, stream, limits) {
+                    Ok(message) => {
+                        responses.extend(handle_message(message, db, client));
+                        if client.closing() {
+                            return (responses, false);
+                        }
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            responses.push(protocol_error(e));
+                        }
+                        return (responses, false);
+                    }
+                }
+            }
+            Err(e) => {
+                responses.push(protocol_error(e));
+                return (responses, false);
             }
-            Err(_) => todo!(),
         }
     }
 }
 
-fn handle_message(message: &Message, stream: &mut TcpStream, db: &mut DB) 
+/// Whether `buffer` starts with a request that has more arguments than
+/// `max_multibulk_len` allows. Only its header needs to have arrived.
+fn too_many_arguments(buffer: &[u8], limits: &Config) -> bool {
+    matches!(parse_array_header(buffer), Ok((_, Some(count))) if count > limits.max_multibulk_len)
+}
+
+/// The reply sent before closing a connection that broke the protocol.
+fn protocol_error(e: impl ToString) -> Vec<u8> {
+    serialise_message(&Message::Error(format!("ERR {}", e.to_string())))
+}
+
+/// Runs one request, returning its serialised reply unless the client has
+/// turned replies off.
+fn handle_message(mut message: Message, db: &DB, client: &mut Client) -> Option<Vec<u8>>
 {
-    let cmd = parse_command(message).unwrap();
-    let response_message = handle_command(&cmd, db);
-    let response_serialised = serialise_message(&response_message);
-    let _ = stream.write_all(response_serialised.as_bytes());
+    replay::record(client.id(), &message);
+    let name = command_name(&message).unwrap_or_default();
+    // Measured up front: parsing moves values the command keeps out of the
+    // message
+    let bytes_in = tracing::enabled!(Level::INFO).then(|| serialised_len(&message));
+    // Only executed commands get a span. It stays open until the reply is
+    // serialised, so bytes out can be recorded on it.
+    let mut span = Span::none();
+    let response_message = match parse_command(&mut message) {
+        Ok(mut cmd) => {
+            span = info_span!(
+                "command",
+                name = %name,
+                keys = cmd.keys().len(),
+                bytes_in = field::Empty,
+                bytes_out = field::Empty,
+                duration_us = field::Empty,
+            );
+            if let Some(bytes_in) = bytes_in {
+                span.record("bytes_in", bytes_in);
+            }
+            let _entered = span.enter();
+            let budget = config::get().command_budget;
+            let start = Instant::now();
+            let response = {
+                let _scope = scope(Subsystem::Commands);
+                handle_command(&mut cmd, db, client, budget)
+            };
+            let elapsed = start.elapsed();
+            span.record("duration_us", elapsed.as_micros() as u64);
+            for key in cmd.keys() {
+                STATS.record_key_op(key);
+            }
+            if over_budget(start, budget) {
+                eprintln!("Slow command: {} took {:?}", name, elapsed);
+            }
+            STATS.record_call(&name, elapsed, matches!(response, Message::Error(_)));
+            response
+        }
+        Err(e) => {
+            STATS.record_rejected(&name);
+            Message::Error(format!("ERR {}", e))
+        }
+    };
+    if let Message::Error(error) = &response_message {
+        STATS.record_error(error);
+    }
     // println!("{:?}", response_message);
-    // println!("{:?}", String::from_utf8_lossy(response_serialised.as_bytes()));
-}
\ No newline at end of file
+    let reply = (client.should_reply() && !client.closing()).then(|| serialise_message(&response_message));
+    span.record("bytes_out", reply.as_ref().map_or(0, Vec::len));
+    reply
+}
+
+/// Sends a batch of serialised responses with as few syscalls as possible.
+fn write_responses(stream: &mut impl Write, responses: &[Vec<u8>]) -> io::Result<()> {
+    let mut slices: Vec<IoSlice> = responses.iter().map(|response| IoSlice::new(response)).collect();
+    let mut slices = slices.as_mut_slice();
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+    use std::net::Shutdown;
+    use std::sync::Arc;
+
+    use tracing::field::{Field, Visit};
+    use tracing::{span, Event, Metadata, Subscriber};
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn ping(mut stream: impl Read + Write) -> Vec<u8> {
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let mut reply = [0; 16];
+        let n = stream.read(&mut reply).unwrap();
+        reply[..n].to_vec()
+    }
+
+    #[test]
+    fn test_tcp_and_unix_listeners() {
+        let path = std::env::temp_dir().join(format!("redirs-test-{}.sock", std::process::id()));
+        // A stale socket from an earlier run is replaced
+        drop(UnixListener::bind(&path));
+        let config = Config { port: "0".to_string(), unix_socket: Some(path.clone()), ..Config::default() };
+        let mut listeners = bind(&config).unwrap();
+        assert_eq!(listeners.len(), 1);
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        listeners.push(Listener::Tcp(tcp));
+        let db: DB = Arc::new(MemoryStorage::new());
+        for listener in listeners {
+            let db = db.clone();
+            thread::spawn(move || listener.serve(db));
+        }
+
+        assert_eq!(ping(UnixStream::connect(&path).unwrap()), b"$4\r\nPONG\r\n");
+        assert_eq!(ping(TcpStream::connect(address).unwrap()), b"$4\r\nPONG\r\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_never_removes_regular_files() {
+        let path = std::env::temp_dir().join(format!("redirs-test-{}.file", std::process::id()));
+        fs::write(&path, b"data").unwrap();
+        let config = Config { port: "0".to_string(), unix_socket: Some(path.clone()), ..Config::default() };
+        assert!(bind(&config).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_too_many_arguments() {
+        let limits = Config { max_multibulk_len: 2, ..Config::default() };
+        assert!(!too_many_arguments(b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n", &limits));
+        assert!(too_many_arguments(b"*3\r\n", &limits));
+        assert!(!too_many_arguments(b"*3", &limits));
+        assert!(!too_many_arguments(b"+OK\r\n", &limits));
+    }
+
+    #[test]
+    fn test_hang_up_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (_idle_client, _busy_client) = (TcpStream::connect(address).unwrap(), TcpStream::connect(address).unwrap());
+        let idle = Arc::new(listener.accept().unwrap().0);
+        let busy = Arc::new(listener.accept().unwrap().0);
+
+        let connections = Connections::default();
+        connections.register(1, idle.clone()).idle.store(true, Ordering::Relaxed);
+        connections.register(2, busy.clone());
+        // Registering shares the stream instead of duplicating its socket
+        assert_eq!(Arc::strong_count(&idle), 2);
+        connections.hang_up(true, Shutdown::Read);
+        let mut chunk = [0; 16];
+        assert_eq!((&*idle).read(&mut chunk).unwrap(), 0);
+        busy.set_nonblocking(true).unwrap();
+        assert_eq!((&*busy).read(&mut chunk).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        connections.remove(1);
+        assert_eq!(Arc::strong_count(&idle), 1);
+        connections.hang_up(false, Shutdown::Both);
+        busy.set_nonblocking(false).unwrap();
+        assert_eq!((&*busy).read(&mut chunk).unwrap(), 0);
+    }
+
+    #[cfg(feature = "alloc-tracking")]
+    #[test]
+    fn test_stored_values_are_charged_to_storage() {
+        use crate::allocator::{self, STORAGE_TEST_LOCK};
+
+        let _lock = STORAGE_TEST_LOCK.lock().unwrap();
+        let allocated = |subsystem: Subsystem| allocator::stats().unwrap()[subsystem as usize].allocated;
+        let db = crate::storage::open(&Config::default()).unwrap();
+        let value_len = 4 << 20;
+        let (protocol, storage) = (allocated(Subsystem::Protocol), allocated(Subsystem::Storage));
+
+        // Read and handled under the protocol scope, as on a connection
+        let _scope = scope(Subsystem::Protocol);
+        let bulk = |string: &[u8]| Message::BulkString(Some(string.to_vec()));
+        let request = Message::Array(Some(vec![bulk(b"SET"), bulk(b"key"), bulk(&vec![b'v'; value_len])]));
+        assert_eq!(handle_message(request, &db, &mut Client::default()), Some(b"$2\r\nOK\r\n".to_vec()));
+
+        assert!(allocated(Subsystem::Storage) >= storage + value_len);
+        assert!(allocated(Subsystem::Protocol) < protocol + value_len / 2);
+    }
+
+    /// A span's fields, formatted with `Debug`
+    type Fields = HashMap<&'static str, String>;
+
+    /// Keeps the name and recorded fields of every span, in the order they
+    /// were opened
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<(&'static str, Fields)>>>);
+
+    struct FieldRecorder<'a>(&'a mut Fields);
+
+    impl Visit for FieldRecorder<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = HashMap::new();
+            span.record(&mut FieldRecorder(&mut fields));
+            spans.push((span.metadata().name(), fields));
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut FieldRecorder(&mut spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn test_command_spans() {
+        let db: DB = Arc::new(MemoryStorage::new());
+        let request = |args: &[&[u8]]| {
+            Message::Array(Some(args.iter().map(|arg| Message::BulkString(Some(arg.to_vec()))).collect()))
+        };
+        let set = request(&[b"SET", b"key", b"value"]);
+        let get = request(&[b"GET", b"key"]);
+        let lengths = [(serialise_message(&set).len(), 8), (serialise_message(&get).len(), 11)];
+
+        let recorder = SpanRecorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut client = Client::default();
+            assert_eq!(handle_message(set, &db, &mut client).unwrap(), b"$2\r\nOK\r\n");
+            assert_eq!(handle_message(get, &db, &mut client).unwrap(), b"$5\r\nvalue\r\n");
+        });
+
+        let spans = recorder.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        for ((span, fields), (name, (bytes_in, bytes_out))) in spans.iter().zip(["set", "get"].into_iter().zip(lengths)) {
+            assert_eq!(*span, "command");
+            assert_eq!(fields["name"], name);
+            assert_eq!(fields["keys"], "1");
+            assert_eq!(fields["bytes_in"], bytes_in.to_string());
+            assert_eq!(fields["bytes_out"], bytes_out.to_string());
+            assert!(fields["duration_us"].parse::<u64>().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_write_responses() {
+        let responses = vec![b"+OK\r\n".to_vec(), b"$-1\r\n".to_vec(), b":1\r\n".to_vec()];
+        let mut out = Vec::new();
+        write_responses(&mut out, &responses).unwrap();
+        assert_eq!(out, b"+OK\r\n$-1\r\n:1\r\n");
+    }
+
+    /// Counts the write calls reaching the socket, one syscall each
+    struct CountingStream {
+        stream: TcpStream,
+        writes: u64,
+    }
+
+    impl Write for CountingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.stream.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.writes += 1;
+            self.stream.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    /// Sends `batches` pipelined batches of replies to a draining client and
+    /// returns the write syscalls and time it took.
+    fn run_writes<F>(batches: usize, batch: &[Vec<u8>], write: F) -> (u64, Duration)
+    where
+        F: Fn(&mut CountingStream, &[Vec<u8>]) -> io::Result<()>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = CountingStream { stream, writes: 0 };
+        let drain = thread::spawn(move || io::copy(&mut client, &mut io::sink()).unwrap());
+
+        let start = Instant::now();
+        for _ in 0..batches {
+            write(&mut server, batch).unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        server.stream.shutdown(Shutdown::Both).unwrap();
+        drain.join().unwrap();
+        (server.writes, elapsed)
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn bench_pipelined_writes() {
+        const BATCHES: usize = 10_000;
+        const PIPELINE: usize = 16;
+        let batch: Vec<Vec<u8>> = (0..PIPELINE).map(|_| b"$5\r\nhello\r\n".to_vec()).collect();
+
+        let (per_reply_syscalls, per_reply_time) = run_writes(BATCHES, &batch, |stream, responses| {
+            responses.iter().try_for_each(|response| stream.write_all(response))
+        });
+        let (vectored_syscalls, vectored_time) = run_writes(BATCHES, &batch, |stream, responses| {
+            write_responses(stream, responses)
+        });
+
+        println!(
+            "{} batches of {} replies\n  write_all per reply: {} write syscalls, {:?}\n  write_vectored per batch: {} write syscalls, {:?}",
+            BATCHES, PIPELINE, per_reply_syscalls, per_reply_time, vectored_syscalls, vectored_time
+        );
+        assert!(vectored_syscalls < per_reply_syscalls);
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Set by SHUTDOWN, waited on by the listeners.
+pub(crate) static SHUTDOWN: Shutdown = Shutdown::new();
+
+/// A shutdown request: once it is made, listeners stop accepting, clients
+/// are let go at their next request boundary, and the server exits when
+/// they have all gone or the grace period is over.
+pub(crate) struct Shutdown {
+    deadline: Mutex<Option<Instant>>,
+    requested: Condvar,
+    /// Mirrors `deadline.is_some()` so connections can check it for free
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    const fn new() -> Self {
+        Self {
+            deadline: Mutex::new(None),
+            requested: Condvar::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts shutting down, giving clients `grace` to finish. Returns false
+    /// if a shutdown is already under way.
+    pub fn begin(&self, grace: Duration) -> bool {
+        let mut deadline = self.deadline.lock().unwrap();
+        if deadline.is_some() {
+            return false;
+        }
+        *deadline = Some(Instant::now() + grace);
+        self.draining.store(true, Ordering::Relaxed);
+        self.requested.notify_all();
+        true
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until a shutdown is requested, returning its deadline.
+    pub fn wait(&self) -> Instant {
+        let deadline = self.deadline.lock().unwrap();
+        let deadline = self.requested.wait_while(deadline, |deadline| deadline.is_none()).unwrap();
+        deadline.expect("woken by a shutdown request")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(!shutdown.draining());
+        let waiter = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || shutdown.wait())
+        };
+
+        let before = Instant::now();
+        assert!(shutdown.begin(Duration::from_secs(10)));
+        assert!(shutdown.draining());
+        let deadline = waiter.join().unwrap();
+        assert!(deadline >= before + Duration::from_secs(10));
+
+        // The first request's deadline stands
+        assert!(!shutdown.begin(Duration::ZERO));
+        assert_eq!(shutdown.wait(), deadline);
+    }
+}
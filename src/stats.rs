@@ -0,0 +1,336 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::command::COMMANDS;
+use crate::config;
+use crate::server::Transport;
+use crate::util::glob_match;
+
+/// Server-wide statistics, updated by the dispatch layer and reported by INFO.
+pub(crate) static STATS: LazyLock<Stats> = LazyLock::new(Stats::new);
+
+#[derive(Default)]
+struct CommandStats {
+    calls: AtomicU64,
+    usec: AtomicU64,
+    rejected_calls: AtomicU64,
+    failed_calls: AtomicU64,
+}
+
+/// Operations on the keys matching one of the configured stats prefixes
+struct PrefixStats {
+    pattern: String,
+    ops: AtomicU64,
+    window: Mutex<OpsWindow>,
+}
+
+/// Counts operations per whole second since the stats were created, keeping
+/// the current second and the one before it.
+#[derive(Default)]
+struct OpsWindow {
+    second: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl OpsWindow {
+    fn roll(&mut self, second: u64) {
+        if second != self.second {
+            self.previous = if second == self.second + 1 { self.current } else { 0 };
+            self.current = 0;
+            self.second = second;
+        }
+    }
+}
+
+/// What STATS PREFIX reports for a pattern besides its keys
+pub(crate) struct PrefixOps<'a> {
+    pub pattern: &'a str,
+    pub ops: u64,
+    /// Operations in the last whole second
+    pub ops_per_sec: u64,
+}
+
+pub(crate) struct Stats {
+    /// Indexed like `COMMANDS`
+    commands: Vec<CommandStats>,
+    /// Error replies by error code. Errors are off the hot path, so a sharded
+    /// map is fine here where the per-command counters have to be atomics.
+    errors: DashMap<String, AtomicU64>,
+    /// Open connections, indexed like `Transport::ALL`
+    clients: [AtomicU64; Transport::ALL.len()],
+    prefixes: Vec<PrefixStats>,
+    started: Instant,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self::with_prefixes(&config::get().stats_prefixes)
+    }
+
+    fn with_prefixes(patterns: &[String]) -> Self {
+        Self {
+            commands: COMMANDS.iter().map(|_| CommandStats::default()).collect(),
+            errors: DashMap::new(),
+            clients: Default::default(),
+            prefixes: patterns
+                .iter()
+                .map(|pattern| PrefixStats {
+                    pattern: pattern.clone(),
+                    ops: AtomicU64::new(0),
+                    window: Mutex::new(OpsWindow::default()),
+                })
+                .collect(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Records a command on `key` against every stats prefix it matches.
+    pub fn record_key_op(&self, key: &[u8]) {
+        if self.prefixes.is_empty() {
+            return;
+        }
+        let second = self.started.elapsed().as_secs();
+        for prefix in &self.prefixes {
+            if glob_match(prefix.pattern.as_bytes(), key) {
+                prefix.ops.fetch_add(1, Ordering::Relaxed);
+                let mut window = prefix.window.lock().unwrap();
+                window.roll(second);
+                window.current += 1;
+            }
+        }
+    }
+
+    /// The operation counts for each stats prefix, in the order configured.
+    pub fn prefix_ops(&self) -> Vec<PrefixOps<'_>> {
+        let second = self.started.elapsed().as_secs();
+        self.prefixes
+            .iter()
+            .map(|prefix| {
+                let mut window = prefix.window.lock().unwrap();
+                window.roll(second);
+                PrefixOps {
+                    pattern: &prefix.pattern,
+                    ops: prefix.ops.load(Ordering::Relaxed),
+                    ops_per_sec: window.previous,
+                }
+            })
+            .collect()
+    }
+
+    fn clients(&self, transport: Transport) -> &AtomicU64 {
+        &self.clients[Transport::ALL.iter().position(|t| *t == transport).unwrap()]
+    }
+
+    pub fn record_connect(&self, transport: Transport) {
+        self.clients(transport).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disconnect(&self, transport: Transport) {
+        self.clients(transport).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connected_clients(&self) -> u64 {
+        self.clients.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    fn command(&self, name: &str) -> Option<&CommandStats> {
+        COMMANDS
+            .iter()
+            .position(|command| command.name == name)
+            .map(|i| &self.commands[i])
+    }
+
+    /// Records an executed command; `failed` if it replied with an error.
+    pub fn record_call(&self, name: &str, duration: Duration, failed: bool) {
+        if let Some(stats) = self.command(name) {
+            stats.calls.fetch_add(1, Ordering::Relaxed);
+            stats.usec.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+            if failed {
+                stats.failed_calls.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Records a command refused before execution, e.g. for bad arguments.
+    pub fn record_rejected(&self, name: &str) {
+        if let Some(stats) = self.command(name) {
+            stats.rejected_calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an error reply under its code, the first word of the message.
+    pub fn record_error(&self, error: &str) {
+        let code = error.split(' ').next().unwrap_or_default();
+        if let Some(count) = self.errors.get(code) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.errors
+            .entry(code.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the requested INFO sections; all of them if none are named.
+    pub fn info(&self, sections: &[&[u8]]) -> String {
+        let wants = |section: &str| {
+            sections.is_empty()
+                || [section, "all", "everything"]
+                    .iter()
+                    .any(|name| sections.iter().any(|s| s.eq_ignore_ascii_case(name.as_bytes())))
+        };
+
+        let mut info = String::new();
+        if wants("clients") {
+            self.write_clients(&mut info);
+        }
+        if wants("commandstats") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            self.write_commandstats(&mut info);
+        }
+        if wants("errorstats") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            self.write_errorstats(&mut info);
+        }
+        info
+    }
+
+    fn write_clients(&self, info: &mut String) {
+        info.push_str("# Clients\r\n");
+        let _ = write!(info, "connected_clients:{}\r\n", self.connected_clients());
+        for transport in Transport::ALL {
+            let count = self.clients(transport).load(Ordering::Relaxed);
+            let _ = write!(info, "{}_clients:{}\r\n", transport.name(), count);
+        }
+    }
+
+    fn write_commandstats(&self, info: &mut String) {
+        info.push_str("# Commandstats\r\n");
+        for (command, stats) in COMMANDS.iter().zip(&self.commands) {
+            let calls = stats.calls.load(Ordering::Relaxed);
+            let rejected_calls = stats.rejected_calls.load(Ordering::Relaxed);
+            if calls == 0 && rejected_calls == 0 {
+                continue;
+            }
+            let usec = stats.usec.load(Ordering::Relaxed);
+            let usec_per_call = if calls == 0 { 0.0 } else { usec as f64 / calls as f64 };
+            let _ = write!(
+                info,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                command.name,
+                calls,
+                usec,
+                usec_per_call,
+                rejected_calls,
+                stats.failed_calls.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    fn write_errorstats(&self, info: &mut String) {
+        info.push_str("# Errorstats\r\n");
+        let mut errors: Vec<_> = self
+            .errors
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+        errors.sort();
+        for (code, count) in errors {
+            let _ = write!(info, "errorstat_{}:count={}\r\n", code, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_commandstats() {
+        let stats = Stats::new();
+        stats.record_call("get", Duration::from_micros(3), false);
+        stats.record_call("get", Duration::from_micros(2), true);
+        stats.record_rejected("get");
+        stats.record_rejected("set");
+        stats.record_call("nosuchcommand", Duration::from_micros(1), false);
+
+        assert_eq!(
+            stats.info(&[b"commandstats"]),
+            "# Commandstats\r\n\
+             cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0\r\n\
+             cmdstat_get:calls=2,usec=5,usec_per_call=2.50,rejected_calls=1,failed_calls=1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_errorstats() {
+        let stats = Stats::new();
+        stats.record_error("ERR Unkown command: foo");
+        stats.record_error("ERR Invalid arguments");
+        stats.record_error("WRONGTYPE Operation against a key holding the wrong kind of value");
+
+        assert_eq!(
+            stats.info(&[b"ERRORSTATS"]),
+            "# Errorstats\r\nerrorstat_ERR:count=2\r\nerrorstat_WRONGTYPE:count=1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_clients() {
+        let stats = Stats::new();
+        stats.record_connect(Transport::Tcp);
+        stats.record_connect(Transport::Tcp);
+        stats.record_connect(Transport::Unix);
+        stats.record_disconnect(Transport::Tcp);
+
+        assert_eq!(
+            stats.info(&[b"clients"]),
+            "# Clients\r\nconnected_clients:2\r\ntcp_clients:1\r\nunix_clients:1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_prefix_ops() {
+        let stats = Stats::with_prefixes(&["tenant:a:*".to_string(), "tenant:*".to_string()]);
+        stats.record_key_op(b"tenant:a:1");
+        stats.record_key_op(b"tenant:b:1");
+        stats.record_key_op(b"session:1");
+
+        let ops: Vec<_> = stats.prefix_ops().iter().map(|prefix| (prefix.pattern, prefix.ops)).collect();
+        assert_eq!(ops, [("tenant:a:*", 1), ("tenant:*", 2)]);
+    }
+
+    #[test]
+    fn test_ops_window() {
+        let mut window = OpsWindow { current: 5, ..OpsWindow::default() };
+        window.roll(0);
+        assert_eq!(window.previous, 0);
+        window.roll(1);
+        assert_eq!((window.previous, window.current), (5, 0));
+        window.current = 3;
+        // An idle second in between means nothing happened in the last one
+        window.roll(3);
+        assert_eq!((window.previous, window.current), (0, 0));
+    }
+
+    #[test]
+    fn test_all_sections() {
+        let stats = Stats::new();
+        assert_eq!(
+            stats.info(&[]),
+            "# Clients\r\nconnected_clients:0\r\ntcp_clients:0\r\nunix_clients:0\r\n\r\n\
+             # Commandstats\r\n\r\n# Errorstats\r\n"
+        );
+        assert_eq!(stats.info(&[b"all"]), stats.info(&[]));
+        assert_eq!(stats.info(&[b"server"]), "");
+    }
+}
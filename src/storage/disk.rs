@@ -0,0 +1,74 @@
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{Storage, StorageError, Value};
+
+/// Disk-backed engine for datasets that don't fit in memory.
+pub(crate) struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let db = sled::open(path)?;
+        Ok(Self { db })
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        Ok(self.db.get(key)?.map(|value| Arc::new(value.to_vec())))
+    }
+
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError> {
+        self.db.update_and_fetch(key, |current| {
+            let mut value = current.map(<[u8]>::to_vec).unwrap_or_default();
+            update(&mut value);
+            Some(value)
+        })?;
+        Ok(())
+    }
+
+    fn del(&self, key: &[u8]) -> Result<bool, StorageError> {
+        Ok(self.db.remove(key)?.is_some())
+    }
+
+    fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError> {
+        // sled values are reference counted in memory, but each key still
+        // gets its own copy on disk
+        let Some(value) = self.db.get(source)? else {
+            return Ok(false);
+        };
+        if replace {
+            self.db.insert(destination, value)?;
+            return Ok(true);
+        }
+        Ok(self.db.compare_and_swap(destination, None as Option<&[u8]>, Some(value))?.is_ok())
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError> {
+        for key in self.db.iter().keys() {
+            if visit(&key?).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
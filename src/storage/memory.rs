@@ -0,0 +1,118 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+
+use super::{Storage, StorageError, Value};
+
+/// The default engine: everything lives in a concurrent in-memory hashmap.
+///
+/// Values are reference counted so COPY and readers can share them. Writes
+/// through `update` copy a shared value first, so copies never see each
+/// other's changes.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    map: DashMap<Vec<u8>, Arc<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        Ok(self.map.get(key).map(|value| Arc::clone(value.value())))
+    }
+
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        self.map.insert(key.into(), Arc::new(value));
+        Ok(())
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError> {
+        // Avoid allocating a key for the common case of an existing value
+        if let Some(mut value) = self.map.get_mut(key) {
+            update(Arc::make_mut(value.value_mut()));
+            return Ok(());
+        }
+        update(Arc::make_mut(self.map.entry(key.into()).or_default().value_mut()));
+        Ok(())
+    }
+
+    fn del(&self, key: &[u8]) -> Result<bool, StorageError> {
+        Ok(self.map.remove(key).is_some())
+    }
+
+    fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError> {
+        // Take the value out before touching the destination: both keys may
+        // live in the same shard
+        let Some(value) = self.map.get(source).map(|value| Arc::clone(value.value())) else {
+            return Ok(false);
+        };
+        match self.map.entry(destination.into()) {
+            Entry::Occupied(_) if !replace => return Ok(false),
+            Entry::Occupied(mut entry) => {
+                entry.insert(value);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+        }
+        Ok(true)
+    }
+
+    fn refcount(&self, key: &[u8]) -> Result<Option<usize>, StorageError> {
+        Ok(self.map.get(key).map(|value| Arc::strong_count(value.value())))
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError> {
+        let _ = self.map.iter().try_for_each(|entry| visit(entry.key()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_copy_shares_until_written() {
+        let storage = MemoryStorage::new();
+        storage.set(b"source", vec![7; 1024]).unwrap();
+        assert!(storage.copy(b"source", b"copy", false).unwrap());
+        let source = storage.get(b"source").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&source, &storage.get(b"copy").unwrap().unwrap()));
+
+        // Readers keep the value they were given
+        storage.update(b"source", &mut |value| value[1] = 1).unwrap();
+        assert_eq!(source[1], 7);
+        assert!(!Arc::ptr_eq(&source, &storage.get(b"source").unwrap().unwrap()));
+
+        storage.update(b"copy", &mut |value| value[0] = 0).unwrap();
+        assert_eq!(storage.get(b"source").unwrap().unwrap()[0], 7);
+        assert_eq!(storage.get(b"copy").unwrap().unwrap()[0], 0);
+    }
+
+    #[test]
+    fn test_del() {
+        let storage = MemoryStorage::new();
+        storage.set(b"key", b"value".to_vec()).unwrap();
+        assert!(storage.del(b"key").unwrap());
+        assert!(!storage.del(b"key").unwrap());
+        assert_eq!(storage.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_copy_replace() {
+        let storage = MemoryStorage::new();
+        storage.set(b"a", b"1".to_vec()).unwrap();
+        storage.set(b"b", b"2".to_vec()).unwrap();
+        assert!(!storage.copy(b"a", b"b", false).unwrap());
+        assert!(!storage.copy(b"missing", b"c", true).unwrap());
+        assert!(storage.copy(b"a", b"b", true).unwrap());
+        assert_eq!(storage.get(b"b").unwrap().as_deref(), Some(&b"1".to_vec()));
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+#[cfg(feature = "alloc-tracking")]
+use crate::allocator::{recharge, scope, Subsystem};
+use crate::config::{Config, StorageEngine};
+
+mod memory;
+pub(crate) use memory::MemoryStorage;
+#[cfg(feature = "sled")]
+mod disk;
+#[cfg(feature = "sled")]
+pub(crate) use disk::SledStorage;
+#[cfg(feature = "sled")]
+mod write_behind;
+#[cfg(feature = "sled")]
+pub(crate) use write_behind::WriteBehindStorage;
+
+/// Shared handle to whichever storage engine the server was started with.
+pub(crate) type DB = Arc<dyn Storage>;
+
+/// A value read from storage. Engines that keep values in memory hand out
+/// the stored value itself; a later write to the key copies it first, so
+/// the reader's view never changes.
+pub(crate) type Value = Arc<Vec<u8>>;
+
+#[derive(Debug, Error)]
+pub(crate) enum StorageError {
+    #[error("Storage backend failure: {0}")]
+    #[cfg_attr(not(feature = "sled"), allow(dead_code))]
+    Backend(String),
+
+    #[error("Storage engine not supported by this build: {0}")]
+    #[cfg_attr(feature = "sled", allow(dead_code))]
+    Unsupported(String),
+}
+
+/// Opens the storage engine selected in the config.
+pub(crate) fn open(config: &Config) -> Result<DB, StorageError> {
+    match config.storage_engine {
+        StorageEngine::Memory => Ok(shared(MemoryStorage::new())),
+        #[cfg(feature = "sled")]
+        StorageEngine::Sled if config.write_behind_backlog > 0 => Ok(shared(
+            WriteBehindStorage::open(&config.dir, config.write_behind_backlog, &config.bio_cpulist)?,
+        )),
+        #[cfg(feature = "sled")]
+        StorageEngine::Sled => Ok(shared(SledStorage::open(&config.dir)?)),
+        #[cfg(not(feature = "sled"))]
+        StorageEngine::Sled => Err(StorageError::Unsupported("sled".to_string())),
+    }
+}
+
+#[cfg(not(feature = "alloc-tracking"))]
+fn shared(engine: impl Storage + 'static) -> DB {
+    Arc::new(engine)
+}
+
+#[cfg(feature = "alloc-tracking")]
+fn shared(engine: impl Storage + 'static) -> DB {
+    Arc::new(Tracked(engine))
+}
+
+/// Charges everything an engine allocates to storage in MEMORY STATS,
+/// along with the values it is handed to keep.
+#[cfg(feature = "alloc-tracking")]
+struct Tracked<S>(S);
+
+#[cfg(feature = "alloc-tracking")]
+impl<S: Storage> Storage for Tracked<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.get(key)
+    }
+
+    fn set(&self, key: &[u8], mut value: Vec<u8>) -> Result<(), StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        recharge(&mut value, Subsystem::Storage);
+        self.0.set(key, value)
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.update(key, &mut |value| {
+            update(value);
+            recharge(value, Subsystem::Storage);
+        })
+    }
+
+    fn del(&self, key: &[u8]) -> Result<bool, StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.del(key)
+    }
+
+    fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.copy(source, destination, replace)
+    }
+
+    fn refcount(&self, key: &[u8]) -> Result<Option<usize>, StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.refcount(key)
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.scan(visit)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let _scope = scope(Subsystem::Storage);
+        self.0.flush()
+    }
+}
+
+/// The keyspace operations the command layer needs from a storage engine.
+///
+/// Engines are shared between client threads, so every operation takes
+/// `&self` and is expected to be atomic on its own.
+pub(crate) trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError>;
+    /// Stores `value` at `key`. Engines that keep values in memory store
+    /// this allocation rather than a copy of it.
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError>;
+    /// Replaces the value at `key` with the result of `update`, which starts
+    /// from an empty value if there is none. No other write to `key` can
+    /// land in between, though `update` may be run more than once. Engines
+    /// that keep values in memory update them in place, so capacity that
+    /// `update` reserves is still there the next time.
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError>;
+    /// Removes `key`, returning whether it was there.
+    fn del(&self, key: &[u8]) -> Result<bool, StorageError>;
+    /// Copies the value at `source` to `destination`, unless `destination`
+    /// already has a value and `replace` is false. Returns whether anything
+    /// was copied. Engines that share values make this O(1).
+    fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError>;
+    /// How many references there are to the value at `key`: every key
+    /// sharing it, plus any reader still holding it. Engines that don't
+    /// share values report 1.
+    fn refcount(&self, key: &[u8]) -> Result<Option<usize>, StorageError> {
+        Ok(self.get(key)?.map(|_| 1))
+    }
+    /// Calls `visit` once for every key, in no particular order, until it
+    /// returns `ControlFlow::Break`.
+    fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError>;
+    /// Returns once every write acknowledged so far is durable. Engines that
+    /// don't persist anything have nothing to do.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
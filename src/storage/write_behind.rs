@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+
+use super::{Storage, StorageError, Value};
+use crate::util::spawn_thread;
+
+/// Most writes applied to sled in a single batch
+const MAX_BATCH: usize = 512;
+
+/// Writes not yet on disk, keyed by key, with the seq of the latest write.
+/// A `None` value is a delete.
+type Pending = Arc<DashMap<Vec<u8>, (u64, Option<Vec<u8>>)>>;
+
+/// A queued write. The value is taken from the overlay when the write is
+/// applied, and only if `seq` is still the latest for the key; older writes
+/// are skipped. So the queue never holds copies of values, and writes that
+/// reach it out of order can't leave a stale value on disk.
+struct Write {
+    key: Vec<u8>,
+    seq: u64,
+}
+
+enum Queued {
+    Write(Write),
+    /// Answered once everything queued before it is flushed to disk
+    Flush(SyncSender<Result<(), String>>),
+}
+
+/// Disk-backed engine that acknowledges writes once they are queued.
+///
+/// Writes land in an in-memory `pending` overlay and a bounded queue; a
+/// background thread drains the queue into sled in batches and drops entries
+/// from the overlay once they are on disk. Reads check the overlay first so
+/// clients always see their own writes. When the backlog is full, writers
+/// block until the background thread catches up. Dropping the engine flushes
+/// everything still queued.
+pub(crate) struct WriteBehindStorage {
+    db: sled::Db,
+    pending: Pending,
+    seq: AtomicU64,
+    sender: Option<SyncSender<Queued>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl WriteBehindStorage {
+    /// Opens the engine, pinning its writer thread to `cpus` if any are given.
+    pub fn open(path: &Path, backlog: usize, cpus: &[usize]) -> Result<Self, StorageError> {
+        Ok(Self::with_db(sled::open(path)?, backlog, cpus))
+    }
+
+    fn with_db(db: sled::Db, backlog: usize, cpus: &[usize]) -> Self {
+        let pending = Arc::new(DashMap::new());
+        let (sender, receiver) = sync_channel(backlog);
+        let writer = {
+            let db = db.clone();
+            let pending = Arc::clone(&pending);
+            spawn_thread("write-behind", cpus, move || write_loop(db, pending, receiver))
+        };
+        Self {
+            db,
+            pending,
+            seq: AtomicU64::new(0),
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+}
+
+fn write_loop(db: sled::Db, pending: Pending, receiver: Receiver<Queued>) {
+    // Block for the first write of a batch, then take whatever else is queued
+    while let Ok(first) = receiver.recv() {
+        let mut writes = Vec::new();
+        let mut flushes = Vec::new();
+        for queued in [first].into_iter().chain(receiver.try_iter().take(MAX_BATCH - 1)) {
+            match queued {
+                Queued::Write(write) => writes.push(write),
+                Queued::Flush(done) => flushes.push(done),
+            }
+        }
+
+        let mut batch = sled::Batch::default();
+        for write in &writes {
+            if let Some(entry) = pending.get(&write.key) {
+                if entry.0 == write.seq {
+                    match &entry.1 {
+                        Some(value) => batch.insert(write.key.as_slice(), value.as_slice()),
+                        None => batch.remove(write.key.as_slice()),
+                    }
+                }
+            }
+        }
+        let applied = db.apply_batch(batch);
+        match &applied {
+            // Leave the writes in the overlay so they are still readable
+            Err(e) => eprintln!("Write-behind batch failed: {}", e),
+            Ok(()) => {
+                for write in writes {
+                    pending.remove_if(&write.key, |_, (seq, _)| *seq == write.seq);
+                }
+            }
+        }
+        if !flushes.is_empty() {
+            let flushed = applied.and_then(|_| db.flush()).map(drop).map_err(|e| e.to_string());
+            for done in flushes {
+                let _ = done.send(flushed.clone());
+            }
+        }
+    }
+    if let Err(e) = db.flush() {
+        eprintln!("Write-behind flush failed: {}", e);
+    }
+}
+
+impl Storage for WriteBehindStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Value>, StorageError> {
+        if let Some(entry) = self.pending.get(key) {
+            return Ok(entry.1.clone().map(Arc::new));
+        }
+        Ok(self.db.get(key)?.map(|value| Arc::new(value.to_vec())))
+    }
+
+    fn set(&self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.pending.insert(key.into(), (seq, Some(value)));
+        self.queue(key, seq)
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&mut Vec<u8>)) -> Result<(), StorageError> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        // The entry keeps other writes to the key out until the new value is
+        // in the overlay. Without an entry, sled already has the latest value.
+        match self.pending.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                let (latest, value) = entry.get_mut();
+                *latest = seq;
+                update(value.get_or_insert_default());
+            }
+            Entry::Vacant(entry) => {
+                let mut value = self.db.get(key)?.map(|value| value.to_vec()).unwrap_or_default();
+                update(&mut value);
+                entry.insert((seq, Some(value)));
+            }
+        }
+        // Only queue once the entry is released: the writer needs it, and
+        // may be what a full queue is waiting on
+        self.queue(key, seq)
+    }
+
+    fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> Result<bool, StorageError> {
+        let Some(value) = self.get(source)?.map(Arc::unwrap_or_clone) else {
+            return Ok(false);
+        };
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        // As in update, the entry keeps other writes to the destination out
+        match self.pending.entry(destination.into()) {
+            Entry::Occupied(entry) if !replace && entry.get().1.is_some() => return Ok(false),
+            Entry::Occupied(mut entry) => {
+                entry.insert((seq, Some(value)));
+            }
+            Entry::Vacant(entry) => {
+                if !replace && self.db.contains_key(destination)? {
+                    return Ok(false);
+                }
+                entry.insert((seq, Some(value)));
+            }
+        }
+        self.queue(destination, seq)?;
+        Ok(true)
+    }
+
+    fn del(&self, key: &[u8]) -> Result<bool, StorageError> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        // The delete sits in the overlay until it reaches disk, so reads
+        // don't fall through to the old value
+        match self.pending.entry(key.into()) {
+            Entry::Occupied(entry) if entry.get().1.is_none() => return Ok(false),
+            Entry::Occupied(mut entry) => {
+                entry.insert((seq, None));
+            }
+            Entry::Vacant(entry) => {
+                if !self.db.contains_key(key)? {
+                    return Ok(false);
+                }
+                entry.insert((seq, None));
+            }
+        }
+        self.queue(key, seq)?;
+        Ok(true)
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>) -> Result<(), StorageError> {
+        // Snapshot the queued keys first: the writer may move them to disk
+        // while we iterate, and they must not be visited twice. Queued
+        // deletes hide the key on disk without being visited themselves.
+        let mut queued = HashSet::new();
+        let mut live = Vec::new();
+        for entry in self.pending.iter() {
+            if entry.1.is_some() {
+                live.push(entry.key().clone());
+            }
+            queued.insert(entry.key().clone());
+        }
+        if live.iter().try_for_each(|key| visit(key)).is_break() {
+            return Ok(());
+        }
+        for key in self.db.iter().keys() {
+            let key = key?;
+            if !queued.contains(key.as_ref()) && visit(&key).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let (done, flushed) = sync_channel(1);
+        self.send(Queued::Flush(done))?;
+        flushed
+            .recv()
+            .map_err(|_| StorageError::Backend("write-behind writer stopped".to_string()))?
+            .map_err(StorageError::Backend)
+    }
+}
+
+impl WriteBehindStorage {
+    fn queue(&self, key: &[u8], seq: u64) -> Result<(), StorageError> {
+        self.send(Queued::Write(Write { key: key.into(), seq }))
+    }
+
+    fn send(&self, queued: Queued) -> Result<(), StorageError> {
+        self.sender
+            .as_ref()
+            .and_then(|sender| sender.send(queued).ok())
+            .ok_or_else(|| StorageError::Backend("write-behind queue closed".to_string()))
+    }
+}
+
+impl Drop for WriteBehindStorage {
+    fn drop(&mut self) {
+        // Closing the queue lets the writer drain what's left and flush
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    fn temporary_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_reads_see_queued_writes() {
+        let storage = WriteBehindStorage::with_db(temporary_db(), 4, &[]);
+        for i in 0..100u8 {
+            storage.set(b"key", vec![i]).unwrap();
+        }
+        assert_eq!(storage.get(b"key").unwrap().as_deref(), Some(&vec![99]));
+        assert_eq!(storage.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_scan_visits_each_key_once() {
+        let storage = WriteBehindStorage::with_db(temporary_db(), 16, &[]);
+        for i in 0..100u32 {
+            storage.set(&(i % 10).to_be_bytes(), i.to_be_bytes().to_vec()).unwrap();
+        }
+        let mut keys = Vec::new();
+        storage
+            .scan(&mut |key| {
+                keys.push(key.to_vec());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        keys.sort();
+        assert_eq!(keys, (0..10u32).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        let db = temporary_db();
+        let storage = Arc::new(WriteBehindStorage::with_db(db.clone(), 8, &[]));
+        storage.set(b"log", b">".to_vec()).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        storage.update(b"log", &mut |value| value.push(b'x')).unwrap();
+                        storage.set(b"other", b"value".to_vec()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // No append was lost, and what reaches disk is the latest value
+        let expected = [&b">"[..], &[b'x'; 2000]].concat();
+        assert_eq!(storage.get(b"log").unwrap().as_deref(), Some(&expected));
+        drop(Arc::into_inner(storage).unwrap());
+        assert_eq!(db.get(b"log").unwrap().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_flush_waits_for_backlog() {
+        let db = temporary_db();
+        let storage = WriteBehindStorage::with_db(db.clone(), 1024, &[]);
+        for i in 0..1000u32 {
+            storage.set(&i.to_be_bytes(), b"value".to_vec()).unwrap();
+        }
+        storage.flush().unwrap();
+        assert_eq!(db.len(), 1000);
+        assert!(storage.pending.is_empty());
+    }
+
+    #[test]
+    fn test_copy() {
+        let db = temporary_db();
+        db.insert(b"on-disk", &b"old"[..]).unwrap();
+        let storage = WriteBehindStorage::with_db(db.clone(), 1024, &[]);
+        storage.set(b"source", b"value".to_vec()).unwrap();
+        assert!(!storage.copy(b"source", b"on-disk", false).unwrap());
+        assert!(!storage.copy(b"missing", b"copy", true).unwrap());
+        assert!(storage.copy(b"source", b"copy", false).unwrap());
+        assert!(storage.copy(b"source", b"on-disk", true).unwrap());
+        storage.flush().unwrap();
+        assert_eq!(db.get(b"copy").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(db.get(b"on-disk").unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn test_del() {
+        let db = temporary_db();
+        db.insert(b"on-disk", &b"value"[..]).unwrap();
+        let storage = WriteBehindStorage::with_db(db.clone(), 1024, &[]);
+        storage.set(b"queued", b"value".to_vec()).unwrap();
+        assert!(storage.del(b"on-disk").unwrap());
+        assert!(storage.del(b"queued").unwrap());
+        assert!(!storage.del(b"on-disk").unwrap());
+        assert!(!storage.del(b"missing").unwrap());
+
+        // Deletes still in the overlay hide what is on disk
+        assert_eq!(storage.get(b"on-disk").unwrap(), None);
+        storage.update(b"queued", &mut |value| value.push(b'x')).unwrap();
+        let mut keys = Vec::new();
+        storage
+            .scan(&mut |key| {
+                keys.push(key.to_vec());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(keys, [b"queued".to_vec()]);
+
+        storage.flush().unwrap();
+        assert!(!db.contains_key(b"on-disk").unwrap());
+        assert_eq!(db.get(b"queued").unwrap().as_deref(), Some(&b"x"[..]));
+    }
+
+    #[test]
+    fn test_drop_flushes_backlog() {
+        let db = temporary_db();
+        let storage = WriteBehindStorage::with_db(db.clone(), 1024, &[]);
+        for i in 0..1000u32 {
+            storage.set(&i.to_be_bytes(), b"value".to_vec()).unwrap();
+        }
+        drop(storage);
+        assert_eq!(db.len(), 1000);
+    }
+}
@@ -0,0 +1,77 @@
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::Resource;
+use thiserror::Error;
+#[cfg(feature = "otlp")]
+use tracing_subscriber::layer::SubscriberExt;
+#[cfg(feature = "otlp")]
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::Config;
+
+#[derive(Debug, Error)]
+pub(crate) enum TelemetryError {
+    #[error("--otlp-endpoint needs a build with the otlp feature")]
+    #[cfg_attr(feature = "otlp", allow(dead_code))]
+    Unsupported,
+
+    #[cfg(feature = "otlp")]
+    #[error("Failed to set up the OTLP exporter: {0}")]
+    Exporter(#[from] opentelemetry_otlp::ExporterBuildError),
+
+    #[cfg(feature = "otlp")]
+    #[error("Failed to install the tracing subscriber: {0}")]
+    Subscriber(#[from] tracing_subscriber::util::TryInitError),
+}
+
+/// Exports command spans while it is alive. Dropping it sends whatever is
+/// still buffered.
+#[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+pub(crate) struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: SdkTracerProvider,
+}
+
+/// Starts exporting spans if an OTLP endpoint is configured.
+#[cfg(not(feature = "otlp"))]
+pub(crate) fn init(config: &Config) -> Result<Option<Telemetry>, TelemetryError> {
+    match config.otlp_endpoint {
+        Some(_) => Err(TelemetryError::Unsupported),
+        None => Ok(None),
+    }
+}
+
+/// Starts exporting spans if an OTLP endpoint is configured.
+#[cfg(feature = "otlp")]
+pub(crate) fn init(config: &Config) -> Result<Option<Telemetry>, TelemetryError> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    // Spans are sent in batches from a background thread, off the command path
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("redirs").build())
+        .build();
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("redirs")))
+        .try_init()?;
+    Ok(Some(Telemetry { provider }))
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Failed to flush spans: {}", e);
+        }
+    }
+}
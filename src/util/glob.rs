@@ -0,0 +1,287 @@
+/// Matches `string` against a Redis glob `pattern`:
+///
+/// - `*` matches any run of bytes, including none
+/// - `?` matches exactly one byte
+/// - `[abc]`, `[a-z]` and `[^a-z]` match one byte in (or not in) a class
+/// - `\` matches the following byte literally, in and out of classes
+///
+/// Malformed patterns behave like Redis: an unterminated class runs to the
+/// end of the pattern and a trailing `\` matches a literal backslash.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the most recent `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            backtrack = Some((p, s));
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, string[s]) {
+            p = next;
+            s += 1;
+            continue;
+        }
+        // Every token but `*` consumes exactly one byte, so retrying from the
+        // last `*` with one more byte swallowed is all the backtracking needed
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'*')
+}
+
+/// Matches the single-byte token at `pattern[p]` against `c`, returning the
+/// index of the next token on success.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b'[' => match_class(pattern, p + 1, c),
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+/// Matches `c` against the class whose body starts at `pattern[i]`.
+fn match_class(pattern: &[u8], mut i: usize, c: u8) -> Option<usize> {
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() {
+        match pattern[i] {
+            b']' => break,
+            b'\\' if i + 1 < pattern.len() => {
+                i += 1;
+                matched |= pattern[i] == c;
+            }
+            start if i + 2 < pattern.len() && pattern[i + 1] == b'-' => {
+                let end = pattern[i + 2];
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                matched |= (low..=high).contains(&c);
+                i += 2;
+            }
+            literal => matched |= literal == c,
+        }
+        i += 1;
+    }
+
+    // Skip the closing `]`, if the class had one
+    (matched != negate).then_some((i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_literals() {
+        assert!(matches("", ""));
+        assert!(matches("hello", "hello"));
+        assert!(!matches("hello", "hell"));
+        assert!(!matches("hell", "hello"));
+        assert!(!matches("", "a"));
+        assert!(!matches("Hello", "hello"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("h*o", "hello"));
+        assert!(matches("h*o", "ho"));
+        assert!(matches("h**o", "hello"));
+        assert!(matches("*llo", "hello"));
+        assert!(matches("he*", "hello"));
+        assert!(matches("*l*l*", "hello"));
+        assert!(!matches("h*x", "hello"));
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("???", "abc"));
+        assert!(!matches("???", "ab"));
+        assert!(matches("*?", "a"));
+        assert!(!matches("*?", ""));
+        assert!(matches("user:*:name", "user:1000:name"));
+        assert!(!matches("user:*:name", "user:1000:email"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        // Reversed ranges are accepted
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(matches("[0-9a-f]", "c"));
+        assert!(!matches("[0-9a-f]", "g"));
+        // An empty class matches nothing
+        assert!(!matches("[]", "a"));
+        assert!(matches("[^]", "a"));
+        // Escapes inside classes
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[\\-]", "-"));
+        assert!(!matches("[\\-]", "\\"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(matches("\\?", "?"));
+        assert!(!matches("\\?", "a"));
+        assert!(matches("\\[a]", "[a]"));
+        assert!(matches("a\\\\b", "a\\b"));
+        // A trailing backslash is literal
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn test_malformed_classes() {
+        // An unterminated class runs to the end of the pattern
+        assert!(matches("[abc", "a"));
+        assert!(matches("a[bc", "ac"));
+        assert!(!matches("[abc", "d"));
+        assert!(matches("[a-", "-"));
+    }
+
+    #[test]
+    fn test_pathological_backtracking() {
+        let string = "a".repeat(10_000);
+        let pattern = "a*".repeat(100) + "b";
+        assert!(!matches(&pattern, &string));
+    }
+
+    /// A direct transcription of Redis' recursive `stringmatchlen`, used as
+    /// the oracle for the fuzz test below.
+    fn reference_match(pattern: &[u8], string: &[u8]) -> bool {
+        // Redis never matches an empty string, even against `*` (KEYS special
+        // cases a lone `*` instead); `glob_match` lets stars match it
+        if string.is_empty() {
+            return pattern.iter().all(|&c| c == b'*');
+        }
+        let (mut pattern, mut string) = (pattern, string);
+        while !pattern.is_empty() && !string.is_empty() {
+            match pattern[0] {
+                b'*' => {
+                    while pattern.len() > 1 && pattern[1] == b'*' {
+                        pattern = &pattern[1..];
+                    }
+                    if pattern.len() == 1 {
+                        return true;
+                    }
+                    return (0..=string.len()).any(|i| reference_match(&pattern[1..], &string[i..]));
+                }
+                b'?' => string = &string[1..],
+                b'[' => {
+                    pattern = &pattern[1..];
+                    let negate = pattern.first() == Some(&b'^');
+                    if negate {
+                        pattern = &pattern[1..];
+                    }
+                    let mut matched = false;
+                    loop {
+                        if pattern.is_empty() {
+                            break;
+                        } else if pattern[0] == b'\\' && pattern.len() >= 2 {
+                            pattern = &pattern[1..];
+                            matched |= pattern[0] == string[0];
+                        } else if pattern[0] == b']' {
+                            break;
+                        } else if pattern.len() >= 3 && pattern[1] == b'-' {
+                            let (low, high) = (pattern[0].min(pattern[2]), pattern[0].max(pattern[2]));
+                            matched |= low <= string[0] && string[0] <= high;
+                            pattern = &pattern[2..];
+                        } else {
+                            matched |= pattern[0] == string[0];
+                        }
+                        pattern = &pattern[1..];
+                    }
+                    if matched == negate {
+                        return false;
+                    }
+                    string = &string[1..];
+                    if pattern.is_empty() {
+                        return string.is_empty();
+                    }
+                }
+                b'\\' if pattern.len() >= 2 => {
+                    if pattern[1] != string[0] {
+                        return false;
+                    }
+                    pattern = &pattern[1..];
+                    string = &string[1..];
+                }
+                literal => {
+                    if literal != string[0] {
+                        return false;
+                    }
+                    string = &string[1..];
+                }
+            }
+            pattern = &pattern[1..];
+            if string.is_empty() {
+                while pattern.first() == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                break;
+            }
+        }
+        pattern.is_empty() && string.is_empty()
+    }
+
+    /// Small xorshift generator so the fuzz test is reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, alphabet: &[u8], max_len: usize) -> Vec<u8> {
+            let len = self.next() as usize % (max_len + 1);
+            (0..len).map(|_| alphabet[self.next() as usize % alphabet.len()]).collect()
+        }
+    }
+
+    #[test]
+    fn test_fuzz_against_reference() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200_000 {
+            let pattern = rng.bytes(b"ab*?[]^-\\", 8);
+            let string = rng.bytes(b"ab-]^\\*", 6);
+            assert_eq!(
+                glob_match(&pattern, &string),
+                reference_match(&pattern, &string),
+                "pattern {:?} string {:?}",
+                String::from_utf8_lossy(&pattern),
+                String::from_utf8_lossy(&string),
+            );
+        }
+    }
+}
@@ -0,0 +1,89 @@
+/// Reserves room for `value` to grow to `len` bytes, with spare capacity so
+/// repeated appends are amortised. Like Redis's strings, a value gets as much
+/// spare room as its new length, up to `max_prealloc` bytes, so large values
+/// don't waste up to half their allocation the way doubling would.
+pub(crate) fn reserve_growth(value: &mut Vec<u8>, len: usize, max_prealloc: usize) {
+    if len > value.capacity() {
+        value.reserve_exact(len + len.min(max_prealloc) - value.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+
+    const MAX_PREALLOC: usize = 1024 * 1024;
+
+    /// Appends `count` chunks using `grow` to make room first, returning how
+    /// many times the value had to be reallocated.
+    fn append_chunks(count: usize, chunk: &[u8], grow: impl Fn(&mut Vec<u8>, usize)) -> (Vec<u8>, usize) {
+        let mut value = Vec::new();
+        let mut reallocations = 0;
+        for _ in 0..count {
+            let capacity = value.capacity();
+            let len = value.len() + chunk.len();
+            grow(&mut value, len);
+            value.extend_from_slice(chunk);
+            reallocations += usize::from(value.capacity() != capacity);
+        }
+        (value, reallocations)
+    }
+
+    #[test]
+    fn test_reserve_growth() {
+        let mut value = b"hello".to_vec();
+        reserve_growth(&mut value, 8, MAX_PREALLOC);
+        assert_eq!(value.capacity(), 16);
+        // Already room: nothing changes
+        reserve_growth(&mut value, 16, MAX_PREALLOC);
+        assert_eq!(value.capacity(), 16);
+
+        // Large values only get `max_prealloc` spare bytes
+        let mut value = vec![0; 10 * MAX_PREALLOC];
+        reserve_growth(&mut value, 10 * MAX_PREALLOC + 1, MAX_PREALLOC);
+        assert_eq!(value.capacity(), 11 * MAX_PREALLOC + 1);
+    }
+
+    #[test]
+    fn test_appends_are_amortised() {
+        let (value, reallocations) = append_chunks(100_000, b"0123456789abcdef", |value, len| {
+            reserve_growth(value, len, MAX_PREALLOC)
+        });
+        assert_eq!(value.len(), 1_600_000);
+        // Doubling up to 1 MiB, then a step per MiB
+        assert!(reallocations < 32, "{}", reallocations);
+        assert!(value.capacity() - value.len() <= MAX_PREALLOC);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn bench_append_chunks() {
+        const CHUNKS: usize = 1_000_000;
+        let chunk = b"log line 0123456789\n";
+
+        let start = Instant::now();
+        let (_, exact_reallocations) = append_chunks(CHUNKS, chunk, |value, len| {
+            value.reserve_exact(len - value.len())
+        });
+        let exact = start.elapsed();
+
+        let start = Instant::now();
+        let (value, reallocations) = append_chunks(CHUNKS, chunk, |value, len| {
+            reserve_growth(value, len, MAX_PREALLOC)
+        });
+        let amortised = start.elapsed();
+
+        println!(
+            "Appending {} {}-byte chunks ({} MB):\n  exact growth: {:?}, {} reallocations\n  reserve_growth: {:?}, {} reallocations",
+            CHUNKS,
+            chunk.len(),
+            value.len() / 1_000_000,
+            exact,
+            exact_reallocations,
+            amortised,
+            reallocations,
+        );
+    }
+}
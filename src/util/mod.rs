@@ -0,0 +1,10 @@
+mod bits;
+pub(crate) use bits::{popcount, popcount_bits};
+mod grow;
+pub(crate) use grow::reserve_growth;
+mod glob;
+pub(crate) use glob::glob_match;
+mod range;
+pub(crate) use range::normalise_string_range;
+mod threads;
+pub(crate) use threads::{parse_cpu_list, spawn_thread};
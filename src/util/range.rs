@@ -0,0 +1,100 @@
+use std::ops::Range;
+
+/// Resolves an inclusive `start..=end` index pair, as taken by LRANGE and
+/// ZRANGE, against a sequence of `len` elements. Negative indexes count back
+/// from the end (`-1` is the last element) and out-of-bounds indexes are
+/// clamped. Returns `None` when the range selects nothing, otherwise a
+/// half-open range that can be used to slice the sequence directly.
+pub(crate) fn normalise_range(start: isize, end: isize, len: usize) -> Option<Range<usize>> {
+    let len = isize::try_from(len).unwrap_or(isize::MAX);
+    let start = if start < 0 { start.saturating_add(len) } else { start };
+    let end = if end < 0 { end.saturating_add(len) } else { end };
+
+    let start = start.max(0);
+    let end = end.min(len - 1);
+    if start > end {
+        return None;
+    }
+    Some(start as usize..end as usize + 1)
+}
+
+/// Like `normalise_range`, but with the rule the string commands (GETRANGE,
+/// SUBSTR and BITCOUNT) follow up to Redis 7.2, which is what we match: an
+/// end before the first element is clamped to it instead of selecting
+/// nothing, so `GETRANGE key 0 -100` returns the first byte. Two negative
+/// indexes with the start after the end still select nothing.
+pub(crate) fn normalise_string_range(start: isize, end: isize, len: usize) -> Option<Range<usize>> {
+    if start < 0 && end < 0 && start > end {
+        return None;
+    }
+    let end = if end < 0 {
+        end.saturating_add(isize::try_from(len).unwrap_or(isize::MAX)).max(0)
+    } else {
+        end
+    };
+    normalise_range(start, end, len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_positive_indexes() {
+        assert_eq!(normalise_range(0, 4, 10), Some(0..5));
+        assert_eq!(normalise_range(3, 3, 10), Some(3..4));
+        assert_eq!(normalise_range(0, 9, 10), Some(0..10));
+    }
+
+    #[test]
+    fn test_negative_indexes() {
+        assert_eq!(normalise_range(0, -1, 10), Some(0..10));
+        assert_eq!(normalise_range(-3, -1, 10), Some(7..10));
+        assert_eq!(normalise_range(-10, -10, 10), Some(0..1));
+        assert_eq!(normalise_range(2, -2, 10), Some(2..9));
+    }
+
+    #[test]
+    fn test_clamping() {
+        // End past the last element is clamped
+        assert_eq!(normalise_range(5, 100, 10), Some(5..10));
+        // Start before the first element is clamped
+        assert_eq!(normalise_range(-100, 2, 10), Some(0..3));
+        assert_eq!(normalise_range(-100, 100, 10), Some(0..10));
+        assert_eq!(normalise_range(isize::MIN, isize::MAX, 10), Some(0..10));
+    }
+
+    #[test]
+    fn test_empty_ranges() {
+        // Start after end
+        assert_eq!(normalise_range(5, 2, 10), None);
+        assert_eq!(normalise_range(-1, -2, 10), None);
+        // Start past the last element
+        assert_eq!(normalise_range(10, 20, 10), None);
+        // End before the first element
+        assert_eq!(normalise_range(0, -11, 10), None);
+        assert_eq!(normalise_range(-100, -50, 10), None);
+        // Nothing to select from
+        assert_eq!(normalise_range(0, 0, 0), None);
+        assert_eq!(normalise_range(0, -1, 0), None);
+        assert_eq!(normalise_range(-1, -1, 0), None);
+    }
+
+    #[test]
+    fn test_string_ranges() {
+        // An end before the first element selects it, where lists get nothing
+        assert_eq!(normalise_string_range(0, -11, 10), Some(0..1));
+        assert_eq!(normalise_range(0, -11, 10), None);
+        assert_eq!(normalise_string_range(-100, -50, 10), Some(0..1));
+        assert_eq!(normalise_range(-100, -50, 10), None);
+        assert_eq!(normalise_string_range(isize::MIN, isize::MIN, 10), Some(0..1));
+
+        // Otherwise the rules agree
+        for (start, end) in [(0, 4), (0, -1), (-3, -1), (2, -2), (5, 100), (-100, 2), (5, 2), (-1, -2), (10, 20)] {
+            assert_eq!(normalise_string_range(start, end, 10), normalise_range(start, end, 10), "{}..={}", start, end);
+        }
+        // Nothing to select from
+        assert_eq!(normalise_string_range(0, -100, 0), None);
+        assert_eq!(normalise_string_range(-1, -1, 0), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+use std::thread::{self, JoinHandle};
+
+/// Spawns a named thread, so it can be told apart in `top -H`, `perf` and
+/// debuggers. Linux cuts names at 15 bytes. If `cpus` is not empty, the
+/// thread is pinned to those CPUs. Threads it spawns inherit the pinning.
+pub(crate) fn spawn_thread<F, T>(name: &str, cpus: &[usize], f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let cpus = cpus.to_vec();
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            if !cpus.is_empty() {
+                if let Err(e) = pin_current_thread(&cpus) {
+                    eprintln!("Failed to pin thread {} to CPUs {:?}: {}", thread::current().name().unwrap_or_default(), cpus, e);
+                }
+            }
+            f()
+        })
+        .expect("failed to spawn thread")
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, and every CPU is checked to fit
+    // in it before being set
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no such CPU: {}", cpu)));
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "CPU pinning is only supported on Linux"))
+}
+
+/// Parses a CPU list such as `0-3,8,10-11`, naming only CPUs that are
+/// online.
+pub(crate) fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    parse_cpu_list_below(list, cpu_limit())
+}
+
+/// Parses a CPU list whose ids must all be below `limit`. Ranges are checked
+/// before they are expanded, so `0-1000000000` can't allocate a huge list.
+fn parse_cpu_list_below(list: &str, limit: usize) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+                if start > end || end >= limit {
+                    return None;
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(range.parse().ok().filter(|cpu| *cpu < limit)?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+/// One past the highest CPU id a list may name: the number of CPUs online,
+/// and no more than fit in a `cpu_set_t`.
+#[cfg(target_os = "linux")]
+fn cpu_limit() -> usize {
+    let set_size = libc::CPU_SETSIZE as usize;
+    // SAFETY: sysconf only reads a system setting
+    let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    usize::try_from(online).map_or(set_size, |online| online.min(set_size))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_limit() -> usize {
+    thread::available_parallelism().map_or(1, usize::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list_below("3", 16), Some(vec![3]));
+        assert_eq!(parse_cpu_list_below("0-3,8,10-11", 16), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list_below("2,0-2", 16), Some(vec![0, 1, 2]));
+        for list in ["", "a", "1,", "3-1", "-1", "1-", "16", "0-16", "0-1000000000"] {
+            assert_eq!(parse_cpu_list_below(list, 16), None, "{}", list);
+        }
+
+        // The limit is the CPUs online, capped by what fits in a cpu_set_t
+        assert!((1..=1024).contains(&cpu_limit()));
+        assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+        assert_eq!(parse_cpu_list("0-1000000000"), None);
+    }
+
+    #[test]
+    fn test_spawn_names_thread() {
+        let name = spawn_thread("test-worker", &[], || thread::current().name().map(str::to_string));
+        assert_eq!(name.join().unwrap().as_deref(), Some("test-worker"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_spawn_pins_thread() {
+        fn allowed_cpus() -> Vec<usize> {
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                (0..libc::CPU_SETSIZE as usize).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect()
+            }
+        }
+        // The machine may not let us use every CPU, so pick one it does
+        let cpu = allowed_cpus()[0];
+        let pinned = spawn_thread("test-pinned", &[cpu], allowed_cpus);
+        assert_eq!(pinned.join().unwrap(), [cpu]);
+    }
+}